@@ -3,12 +3,12 @@ use tinkoff_api::models::CandleResolution;
 use tokio_tungstenite::tungstenite::Message;
 use crate::model::*;
 
-use crate:: streaming::entities::Request;
+use crate:: streaming::entities::Tagged;
 
 
-impl Into<Message> for &Request {
+impl Into<Message> for Tagged<'_> {
     fn into(self) -> Message {
-        Message::Text(self.to_string())
+        Message::Text(serde_json::to_string(&self).unwrap())
     }
 }
 
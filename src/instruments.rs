@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::model::Stock;
+
+/// Где лежит кэш справочника инструментов
+pub const CACHE_PATH: &str = ".instruments-cache.json";
+/// Как часто перекачивать справочник
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Справочник инструментов с поиском по тикеру, FIGI, ISIN и названию
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InstrumentIndex {
+    updated: u64,
+    stocks: Vec<Stock>,
+    #[serde(skip)]
+    keys: HashMap<String, usize>,
+    #[serde(skip)]
+    figis: HashMap<String, usize>,
+    /// Инструменты, шаг цены и лот которых пришли с биржи
+    #[serde(skip)]
+    from_exchange: HashSet<String>,
+}
+
+impl InstrumentIndex {
+    pub fn new(stocks: Vec<Stock>) -> Self {
+        let updated = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d|d.as_secs());
+        let mut index = Self { updated, stocks, ..Default::default() };
+        index.build_keys();
+        index
+    }
+
+    fn build_keys(&mut self) {
+        self.keys = HashMap::new();
+        self.figis = self.stocks.iter().enumerate().map(|(i, stock)|(stock.figi.clone(), i)).collect();
+        for (i, stock) in self.stocks.iter().enumerate() {
+            let keys = std::iter::once(&stock.ticker).chain(std::iter::once(&stock.figi)).chain(stock.isin.iter());
+            for key in keys {
+                self.keys.entry(key.to_uppercase()).or_insert(i);
+            }
+        }
+    }
+
+    pub fn stocks(&self) -> &[Stock] {
+        &self.stocks
+    }
+
+    pub fn is_stale(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d|d.as_secs());
+        now.saturating_sub(self.updated) > REFRESH_INTERVAL.as_secs()
+    }
+
+    /// Точный поиск по тикеру, FIGI или ISIN без учета регистра
+    pub fn get(&self, key: &str) -> Option<&Stock> {
+        self.keys.get(&key.trim().to_uppercase()).map(|&i|&self.stocks[i])
+    }
+
+    pub fn by_figi(&self, figi: &str) -> Option<&Stock> {
+        self.figis.get(figi).map(|&i|&self.stocks[i])
+    }
+
+    /// Обновляет шаг цены и лот по данным биржи; `false`, если инструмента не было и он добавлен
+    pub fn update(&mut self, figi: &str, min_increment: f64, lot: u32) -> bool {
+        self.from_exchange.insert(figi.to_owned());
+        match self.figis.get(figi) {
+            Some(&i) => {
+                self.stocks[i].min_increment = min_increment;
+                self.stocks[i].lot = lot;
+                true
+            }
+            None => {
+                self.stocks.push(Stock { min_increment, lot, ..Stock::unknown(figi) });
+                self.build_keys();
+                false
+            }
+        }
+    }
+
+    /// Заменяет справочник более свежим, сохраняя данные биржи: они точнее справочника
+    pub fn merge(&mut self, other: InstrumentIndex) {
+        let from_exchange = std::mem::take(&mut self.from_exchange);
+        let mut stocks = other.stocks;
+        for stock in stocks.iter_mut().filter(|s|from_exchange.contains(&s.figi)) {
+            if let Some(old) = self.by_figi(&stock.figi) {
+                stock.min_increment = old.min_increment;
+                stock.lot = old.lot;
+            }
+        }
+        let known: HashSet<_> = stocks.iter().map(|s|s.figi.clone()).collect();
+        let old = std::mem::take(&mut self.stocks);
+        stocks.extend(old.into_iter().filter(|s|from_exchange.contains(&s.figi) && !known.contains(&s.figi)));
+        *self = Self { updated: other.updated, stocks, from_exchange, ..Default::default() };
+        self.build_keys();
+    }
+
+    /// Нечеткий поиск: сначала точные совпадения, потом тикеры с таким началом,
+    /// потом названия, содержащие все слова запроса, и в конце совпадения с опечатками
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&Stock> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let words: Vec<_> = query.split_whitespace().collect();
+        let mut found: Vec<_> = self.stocks.iter().filter_map(|stock| {
+            let ticker = stock.ticker.to_lowercase();
+            let name = stock.name.to_lowercase();
+            let score = if ticker == query || stock.figi.to_lowercase() == query
+                || stock.isin.as_ref().map(|s|s.to_lowercase()) == Some(query.clone()) {
+                0
+            } else if ticker.starts_with(&query) {
+                1
+            } else if name.starts_with(&query) {
+                2
+            } else if words.iter().all(|w|name.contains(w)) {
+                3
+            } else if similar(&query, &ticker) || words.iter().all(|w|name.split_whitespace().any(|n|similar(w, n))) {
+                4
+            } else {
+                return None
+            };
+            Some((score, stock))
+        }).collect();
+        found.sort_by_key(|(score, stock)|(*score, stock.name.len()));
+        found.into_iter().take(limit).map(|(_, stock)|stock).collect()
+    }
+
+    pub async fn load(path: &str) -> Option<Self> {
+        let mut file = tokio::fs::File::open(path).await.ok()?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await.ok()?;
+        let mut index: Self = serde_json::from_slice(&buf).map_err(|e|log::warn!("broken instruments cache: {}", e)).ok()?;
+        index.build_keys();
+        Some(index)
+    }
+
+    /// Кэш общий для всех трейдеров: пишем во временный файл и подменяем, чтобы никто не прочитал его недописанным
+    pub async fn save(&self, path: &str) -> Result<(), std::io::Error> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let json = serde_json::to_vec(self)?;
+        let tmp = format!("{}.{}-{}.tmp", path, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        tokio::fs::write(&tmp, &json).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, path).await {
+            tokio::fs::remove_file(&tmp).await.unwrap_or(());
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Расстояние Левенштейна по символам
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Слово запроса совпадает с началом слова с опечатками: одна на 4-6 букв, две на длинные слова
+fn similar(query: &str, word: &str) -> bool {
+    let len = query.chars().count();
+    let typos = match len {
+        0..=3 => return false,
+        4..=6 => 1,
+        _ => 2,
+    };
+    let prefix: String = word.chars().take(len).collect();
+    distance(query, &prefix) <= typos
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stock(name: &str, ticker: &str, figi: &str, isin: &str) -> Stock {
+        Stock {
+            name: name.to_owned(),
+            figi: figi.to_owned(),
+            ticker: ticker.to_owned(),
+            isin: Some(isin.to_owned()),
+            min_increment: 0.01,
+            lot: 1,
+        }
+    }
+
+    fn make_index() -> InstrumentIndex {
+        InstrumentIndex::new(vec![
+            stock("Сбербанк России", "SBER", "BBG004730N88", "RU0009029540"),
+            stock("Сбербанк России - привилегированные акции", "SBERP", "BBG0047315Y7", "RU0009029557"),
+            stock("Apple", "AAPL", "BBG000B9XRY4", "US0378331005"),
+        ])
+    }
+
+    #[test]
+    fn test_get() {
+        let index = make_index();
+        assert_eq!(index.get("sber").unwrap().figi, "BBG004730N88");
+        assert_eq!(index.get("BBG000B9XRY4").unwrap().ticker, "AAPL");
+        assert_eq!(index.get("US0378331005").unwrap().ticker, "AAPL");
+        assert!(index.get("SBE").is_none());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut index = make_index();
+        assert!(index.update("BBG004730N88", 0.05, 100));
+        let sber = index.by_figi("BBG004730N88").unwrap();
+        assert_eq!((sber.min_increment, sber.lot), (0.05, 100));
+        assert_eq!(sber.round_price(250.123), 250.1);
+        // инструмента нет в справочнике: без шага цены и лота по нему не торговать
+        assert!(!index.update("SBER", 0.1, 10));
+        assert_eq!(index.by_figi("SBER").map(|s|s.lot), Some(10));
+        assert_eq!(index.get("SBER").unwrap().figi, "BBG004730N88");
+
+        index.merge(make_index());
+        assert_eq!(index.by_figi("BBG004730N88").map(|s|s.lot), Some(100));
+        assert_eq!(index.by_figi("SBER").map(|s|s.lot), Some(10));
+        assert_eq!(index.stocks().len(), 4);
+    }
+
+    #[test]
+    fn test_search() {
+        let index = make_index();
+        let found: Vec<_> = index.search("SBER", 10).into_iter().map(|s|s.ticker.as_str()).collect();
+        assert_eq!(found, vec!["SBER", "SBERP"]);
+        let found: Vec<_> = index.search("сбербанк привилег", 10).into_iter().map(|s|s.ticker.as_str()).collect();
+        assert_eq!(found, vec!["SBERP"]);
+        assert!(index.search("газпром", 10).is_empty());
+        let found: Vec<_> = index.search("сбирбанк", 10).into_iter().map(|s|s.ticker.as_str()).collect();
+        assert_eq!(found, vec!["SBER", "SBERP"]);
+        let found: Vec<_> = index.search("aplle", 10).into_iter().map(|s|s.ticker.as_str()).collect();
+        assert_eq!(found, vec!["AAPL"]);
+    }
+
+    #[test]
+    fn test_serde() {
+        let index = make_index();
+        let json = serde_json::to_string(&index).unwrap();
+        let mut des: InstrumentIndex = serde_json::from_str(&json).unwrap();
+        des.build_keys();
+        assert_eq!(des.get("AAPL").unwrap().name, "Apple");
+        assert!(!des.is_stale());
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let path = std::env::temp_dir().join(format!("instruments-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (first, second) = (make_index(), InstrumentIndex::new(Vec::new()));
+        let (a, b) = tokio::join!(first.save(path), second.save(path));
+        a.unwrap();
+        b.unwrap();
+        // файл всегда целиком от одной из записей
+        let loaded = InstrumentIndex::load(path).await.unwrap();
+        assert!(loaded.stocks().len() == 3 || loaded.stocks().is_empty());
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+}
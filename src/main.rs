@@ -2,6 +2,7 @@ use simplelog::*;
 
 mod model;
 mod convert;
+mod instruments;
 mod streaming;
 mod rest;
 mod strategy;
@@ -1,25 +1,92 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{collections::HashMap, fmt::Display, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 pub type DateTime = chrono::DateTime<chrono::FixedOffset>;
 use async_channel::{Receiver, Sender};
 use chrono::TimeZone;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-pub use crate::streaming::entities::Interval;
+pub use crate::streaming::entities::{Interval, TradeStatus};
+use crate::instruments::InstrumentIndex;
 
-#[derive(Default, Clone)]
+/// Данные без обновлений дольше этого считаются устаревшими
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
 pub struct Market {
-    stocks: HashMap<String, Stock>,
+    instruments: InstrumentIndex,
     state: HashMap<String, StockState>,
+    currencies: HashMap<Currency, f64>,
+    max_age: Duration,
+    /// С какого момента стриминг непрерывно на связи, `None` - связи нет
+    feed_since: Option<SystemTime>,
+}
+
+impl Default for Market {
+    fn default() -> Self {
+        Self {
+            instruments: Default::default(),
+            state: Default::default(),
+            currencies: Default::default(),
+            max_age: DEFAULT_MAX_AGE,
+            feed_since: None,
+        }
+    }
 }
 
 impl Market {
-    pub fn update_stocks(&mut self, stocks: Vec<Stock>) {
-        stocks.into_iter().for_each(|s| {
-            self.stocks.insert(s.figi.to_owned(), s);
-        });
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+    pub fn set_feed(&mut self, since: Option<SystemTime>) {
+        self.feed_since = since;
+    }
+    /// Сколько времени прошло с получения стакана
+    pub fn orderbook_age(&self, figi: &str) -> Option<Duration> {
+        let book = &self.state(figi)?.orderbook;
+        (book.received != UNIX_EPOCH).then(|| book.received.elapsed().unwrap_or_default())
+    }
+    /// Стакан устарел. Стриминг шлет только изменения, поэтому стакан после подключения актуален, пока есть связь
+    pub fn is_stale(&self, figi: &str) -> bool {
+        let book = match self.state(figi) {
+            Some(state) => &state.orderbook,
+            None => return true,
+        };
+        let live = matches!(self.feed_since, Some(since) if book.streamed && book.received >= since);
+        match self.orderbook_age(figi) {
+            Some(age) => !live && age > self.max_age,
+            None => true,
+        }
+    }
+    /// Сколько времени прошло с получения последних свечей
+    pub fn candles_age(&self, figi: &str) -> Option<Duration> {
+        let received = self.state(figi)?.candles_received?;
+        Some(received.elapsed().unwrap_or_default())
+    }
+    /// Свечи старше допустимого или еще не приходили
+    pub fn candles_stale(&self, figi: &str) -> bool {
+        match self.candles_age(figi) {
+            Some(age) => age > self.max_age,
+            None => true,
+        }
+    }
+    pub fn update_stocks(&mut self, instruments: InstrumentIndex) {
+        self.instruments.merge(instruments);
+    }
+    pub fn instruments(&self) -> &InstrumentIndex {
+        &self.instruments
     }
-    pub fn update_portfolio(&mut self, positions: Vec<(String, Position)>, orders: Vec<OrderState>) {
+    /// Забывает позиции и заявки при смене счета
+    pub fn reset_portfolio(&mut self) {
+        self.update_portfolio(Vec::new(), Vec::new(), Vec::new());
+        for state in self.state.values_mut() {
+            state.new_orders.clear();
+        }
+    }
+    pub fn update_portfolio(&mut self, positions: Vec<(String, Position)>, currencies: Vec<(Currency, f64)>, orders: Vec<OrderState>) {
+        self.currencies = currencies.into_iter().collect();
+        for state in self.state.values_mut() {
+            state.position = Default::default();
+        }
         for (figi, position) in positions {
             self.state_mut(&figi).position = position;
         }
@@ -45,26 +112,70 @@ impl Market {
             });
         });
     }
-    pub fn portfolio(&self) -> Vec<(Stock, Position)> {
+    pub fn portfolio(&self, bases: &[Currency]) -> Portfolio {
         log::info!("all stocks: {}", self.state.len());
-        self.state.iter().filter_map(|(figi, state)| {
+        let fx = self.fx_figis();
+        let positions: Vec<_> = self.state.iter().filter_map(|(figi, state)| {
             let position = state.position;
-            if position.balance != 0.0 {
-                Some((self.stock(figi).clone(), position))
+            if position.balance != 0.0 && !fx.contains(figi) {
+                let stock = self.stock(figi).cloned().unwrap_or_else(||Stock::unknown(figi));
+                Some((stock, position))
             } else {
                 None
             }
-        }).collect()
+        }).collect();
+        let mut currencies: Vec<_> = self.currencies.iter()
+            .filter(|(_, &balance)|balance != 0.0)
+            .map(|(&c, &b)|(c, b))
+            .collect();
+        currencies.sort_by_key(|(c, _)|*c);
+        // Стоимость всего портфеля в разрезе валют, затем переводим в рубли
+        let mut amounts = currencies.clone();
+        amounts.extend(positions.iter().filter_map(|(_, p)|Some((p.currency?, p.value()?))));
+        let mut missing_rates: Vec<_> = amounts.iter()
+            .map(|(c, _)|*c)
+            .chain(bases.iter().copied())
+            .filter(|&c|self.rate(c).is_none())
+            .collect();
+        missing_rates.sort();
+        missing_rates.dedup();
+        let rub: f64 = amounts.iter().filter_map(|&(c, v)|Some(v * self.rate(c)?)).sum();
+        let totals = bases.iter().filter_map(|&c|Some((c, rub / self.rate(c)?))).collect();
+        Portfolio { positions, currencies, totals, missing_rates }
     }
-    pub fn stock(&self, figi: &str) -> Stock {
-        self.stocks.get(figi).map(Clone::clone).unwrap_or(Stock {
-            name: figi.to_owned(),
-            figi: figi.to_owned(),
-            ticker: figi.to_owned(),
-            isin: None,
-            min_increment: 0.01,
-            lot: 1,
-        })
+    /// Курс валюты в рублях по стакану валютной пары
+    pub fn rate(&self, currency: Currency) -> Option<f64> {
+        let (ticker, nominal) = match fx_ticker(currency) {
+            Some(fx) => fx,
+            None => return Some(1.0),
+        };
+        let figi = &self.instruments.get(ticker)?.figi;
+        let book = &self.state(figi)?.orderbook;
+        let price = book.mid()
+            .or_else(||book.best(OrderKind::Sell).or_else(||book.best(OrderKind::Buy)).map(|(price, _)|price))?;
+        Some(price / nominal)
+    }
+    /// FIGI валютных пар, по которым нужен стакан для оценки портфеля
+    pub fn fx_figis(&self) -> Vec<String> {
+        let used = self.state.values().filter_map(|s|s.position.currency);
+        let mut currencies: Vec<_> = self.currencies.keys().copied().chain(used).chain(Some(Currency::USD)).collect();
+        currencies.sort();
+        currencies.dedup();
+        currencies.into_iter()
+            .filter_map(fx_ticker)
+            .filter_map(|(ticker, _)|self.instruments.get(ticker))
+            .map(|s|s.figi.clone())
+            .collect()
+    }
+    pub fn stock(&self, figi: &str) -> Option<&Stock> {
+        self.instruments.by_figi(figi)
+    }
+    /// Применяет данные биржи об инструменте
+    pub fn update_instrument(&mut self, figi: &str, trade_status: TradeStatus, min_increment: f64, lot: u32) {
+        self.state_mut(figi).trade_status = Some(trade_status);
+        if !self.instruments.update(figi, min_increment, lot) {
+            log::info!("instrument {} added from exchange info", figi);
+        }
     }
     pub fn state_mut(&mut self, figi: &str) -> &mut StockState {
         self.state.entry(figi.to_owned()).or_insert(Default::default())
@@ -72,6 +183,12 @@ impl Market {
     pub fn state(&self, figi: &str) -> Option<&StockState> {
         self.state.get(figi)
     }
+    pub fn trade_status(&self, figi: &str) -> Option<TradeStatus> {
+        self.state.get(figi).and_then(|s|s.trade_status)
+    }
+    pub fn inwork_orders(&self) -> impl Iterator<Item = &OrderState> {
+        self.state.values().flat_map(|state|state.inwork_orders.values())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -91,17 +208,31 @@ impl std::hash::Hash for Order {
 }
 
 pub type OrderKind = tinkoff_api::models::OperationType;
+pub type OrderStatus = tinkoff_api::models::OrderStatus;
 
 #[derive(Debug, Clone, Default)]
 pub struct StockState {
     pub position: Position,
     pub orderbook: Orderbook,
     pub candles: Vec<Candle>,
+    pub candles_received: Option<SystemTime>,
     pub inwork_orders: HashMap<String, OrderState>,
-    pub new_orders: HashMap<SystemTime, Order>,
+    pub new_orders: HashMap<RequestId, Order>,
+    pub trade_status: Option<TradeStatus>,
 }
 
-#[derive(Debug, Clone)]
+impl StockState {
+    /// Свеча текущего интервала приходит заново при каждом изменении и заменяет предыдущую
+    pub fn update_candle(&mut self, candle: Candle) {
+        match self.candles.last_mut() {
+            Some(last) if last.time == candle.time => *last = candle,
+            _ => self.candles.push(candle),
+        }
+        self.candles_received = Some(SystemTime::now());
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stock {
     pub name: String,
     pub figi: String,
@@ -111,22 +242,136 @@ pub struct Stock {
     pub lot: u32,
 }
 
+impl Stock {
+    /// Заглушка для показа инструмента, о котором ничего не известно; для заявок не годится
+    pub fn unknown(figi: &str) -> Self {
+        Stock {
+            name: figi.to_owned(),
+            figi: figi.to_owned(),
+            ticker: figi.to_owned(),
+            isin: None,
+            min_increment: 0.0,
+            lot: 1,
+        }
+    }
+
+    /// Ближайшая к `price` цена, кратная шагу цены
+    pub fn round_price(&self, price: f64) -> f64 {
+        if self.min_increment <= 0.0 {
+            return price;
+        }
+        let ticks = (price / self.min_increment).round();
+        // убираем хвост от умножения чисел с плавающей точкой
+        (ticks * self.min_increment * 1e9).round() / 1e9
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Position {
     pub lots: i32,
     pub balance: f64,
+    pub currency: Option<Currency>,
+    pub average_price: Option<f64>,
+    pub expected_yield: f64,
+}
+
+impl Position {
+    /// Текущая стоимость позиции в её валюте
+    pub fn value(&self) -> Option<f64> {
+        self.average_price.map(|price|price * self.balance + self.expected_yield)
+    }
+}
+
+/// Портфель с оценкой в базовых валютах
+#[derive(Debug, Clone)]
+pub struct Portfolio {
+    pub positions: Vec<(Stock, Position)>,
+    pub currencies: Vec<(Currency, f64)>,
+    pub totals: Vec<(Currency, f64)>,
+    /// Валюты, для которых пока нет курса: без них итог неполный
+    pub missing_rates: Vec<Currency>,
 }
 
 #[derive(Debug, Clone)]
 pub struct OrderState {
     pub order_id: String,
     pub order: Order,
+    pub status: OrderStatus,
     pub executed: u32,
 }
 
+/// Исполненная часть заявки. Цена - цена заявки: цену сделок брокер не сообщает
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fill {
+    pub limit_price: f64,
+    pub quantity: u32,
+}
+
+pub type Currency = tinkoff_api::models::Currency;
+
+/// Тикер валютной пары к рублю и количество единиц валюты в котировке
+fn fx_ticker(currency: Currency) -> Option<(&'static str, f64)> {
+    Some(match currency {
+        Currency::RUB => return None,
+        Currency::USD => ("USD000UTSTOM", 1.0),
+        Currency::EUR => ("EUR_RUB__TOM", 1.0),
+        Currency::GBP => ("GBPRUB_TOM", 1.0),
+        Currency::HKD => ("HKDRUB_TOM", 1.0),
+        Currency::CHF => ("CHFRUB_TOM", 1.0),
+        Currency::JPY => ("JPYRUB_TOM", 100.0),
+        Currency::CNY => ("CNYRUB_TOM", 1.0),
+        Currency::_TRY => ("TRYRUB_TOM", 1.0),
+    })
+}
+pub type AccountKind = tinkoff_api::models::BrokerAccountType;
+
+/// Брокерский счет: обычный или ИИС
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub kind: AccountKind,
+}
+
+/// Операция по счету из истории брокера
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub time: DateTime,
+    pub figi: Option<String>,
+    pub currency: Currency,
+    /// Сколько денег пришло (+) или ушло (-) со счета
+    pub payment: f64,
+    pub kind: OperationKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperationKind {
+    Trade { kind: OrderKind, trades: Vec<Trade>, commission: f64 },
+    Commission,
+    Dividend,
+    Coupon,
+    Repayment,
+    Tax,
+    Cash,
+    Transfer,
+    Other,
+}
+
+/// Сделка по заявке с реальной ценой исполнения
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub time: DateTime,
+    pub price: f64,
+    pub quantity: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Orderbook {
+    /// Время биржи; у стакана из REST - момент отправки запроса
     pub time: DateTime,
+    /// Когда стакан получен
+    pub received: SystemTime,
+    /// Стакан пришел из стриминга и будет обновляться, пока соединение живо
+    pub streamed: bool,
     pub bids: Vec<(f64, u32)>,
     pub asks: Vec<(f64, u32)>,
 }
@@ -135,12 +380,100 @@ impl Default for Orderbook {
     fn default() -> Self {
         Self {
             time: chrono::FixedOffset::east(0).ymd(2000, 1, 1).and_hms(0,0,0),
+            received: UNIX_EPOCH,
+            streamed: false,
             bids: Vec::new(),
             asks: Vec::new(),
         }
     }
 }
 
+/// Аналитика стакана. Объемы в лотах, `kind` - направление своей заявки
+impl Orderbook {
+    fn levels(&self, kind: OrderKind) -> &[(f64, u32)] {
+        match kind {
+            OrderKind::Buy => &self.asks,
+            OrderKind::Sell => &self.bids,
+        }
+    }
+
+    /// Лучшая цена, по которой исполнится заявка
+    pub fn best(&self, kind: OrderKind) -> Option<(f64, u32)> {
+        self.levels(kind).first().copied()
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => Some((bid.0 + ask.0) / 2.0),
+            _ => None,
+        }
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => Some(ask.0 - bid.0),
+            _ => None,
+        }
+    }
+
+    /// Спред в базисных пунктах от средней цены
+    pub fn spread_bps(&self) -> Option<f64> {
+        Some(self.spread()? / self.mid()? * 10_000.0)
+    }
+
+    /// Средневзвешенная цена исполнения `quantity` лотов; `None`, если объема в стакане не хватает
+    pub fn vwap(&self, kind: OrderKind, quantity: u32) -> Option<f64> {
+        if quantity == 0 {
+            return None;
+        }
+        let mut left = quantity;
+        let mut amount = 0.0;
+        for &(price, volume) in self.levels(kind) {
+            let filled = left.min(volume);
+            amount += price * filled as f64;
+            left -= filled;
+            if left == 0 {
+                return Some(amount / quantity as f64);
+            }
+        }
+        None
+    }
+
+    /// Сколько лотов стоит в пределах `ticks` шагов цены от лучшей
+    pub fn depth(&self, kind: OrderKind, ticks: u32, tick: f64) -> u32 {
+        let best = match self.best(kind) {
+            Some((price, _)) => price,
+            None => return 0,
+        };
+        // половина шага - допуск на погрешность цен в f64
+        let limit = tick * ticks as f64 + tick / 2.0;
+        self.levels(kind).iter()
+            .take_while(|(price, _)|(price - best).abs() <= limit)
+            .map(|(_, volume)|volume)
+            .sum()
+    }
+
+    /// Перевес покупателей на первых `levels` уровнях: от -1 (только продавцы) до 1 (только покупатели)
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let volume = |side: &[(f64, u32)]| side.iter().take(levels).map(|(_, v)|*v as f64).sum::<f64>();
+        let (bids, asks) = (volume(&self.bids), volume(&self.asks));
+        if bids + asks == 0.0 {
+            return None;
+        }
+        Some((bids - asks) / (bids + asks))
+    }
+
+    /// Насколько исполнение `quantity` лотов хуже средней цены, в долях
+    pub fn impact(&self, kind: OrderKind, quantity: u32) -> Option<f64> {
+        let mid = self.mid()?;
+        let vwap = self.vwap(kind, quantity)?;
+        Some(match kind {
+            OrderKind::Buy => (vwap - mid) / mid,
+            OrderKind::Sell => (mid - vwap) / mid,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Candle {
     pub open: f64,
@@ -151,6 +484,31 @@ pub struct Candle {
     pub time: DateTime,
 }
 
+/// Идентификатор запроса к брокеру, уникальный в пределах процесса
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "req-{}", self.0)
+    }
+}
+
+impl std::str::FromStr for RequestId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim_start_matches("req-").parse().map(Self)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServiceHandle<Req, Res> {
     sender: Sender<Req>,
@@ -173,3 +531,124 @@ impl <Req, Res> ServiceHandle<Req, Res> {
 }
 
 pub struct ChannelStopped;
+
+/// Экспоненциальная задержка перед повтором номер `attempt` со случайным разбросом
+pub fn backoff(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let delay = base.checked_mul(1 << attempt.min(16)).map_or(max, |d|d.min(max));
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d|d.subsec_nanos());
+    let random = (nanos % 1000) as f64 / 1000.0;
+    delay / 2 + (delay / 2).mul_f64(random)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stale() {
+        let mut market = Market::default();
+        assert!(market.is_stale("figi"));
+
+        let old = SystemTime::now() - Duration::from_secs(60);
+        market.state_mut("figi").orderbook = Orderbook { received: old, streamed: true, ..Default::default() };
+        assert!(market.is_stale("figi"));
+        // стакан из живого стриминга не меняется, пока нет сделок, и остается актуальным
+        market.set_feed(Some(old - Duration::from_secs(1)));
+        assert!(!market.is_stale("figi"));
+        // после переподключения старый стакан актуален, только пока он моложе предела
+        market.set_feed(Some(SystemTime::now()));
+        assert!(market.is_stale("figi"));
+        market.set_max_age(Duration::from_secs(120));
+        assert!(!market.is_stale("figi"));
+    }
+
+    #[test]
+    fn test_candles() {
+        let candle = |minute: u32, close: f64| Candle {
+            open: 1.0, close, low: 1.0, high: 1.0, volume: 1,
+            time: chrono::DateTime::parse_from_rfc3339(&format!("2021-01-01T10:{:02}:00Z", minute)).unwrap(),
+        };
+        let mut market = Market::default();
+        assert!(market.candles_stale("figi"));
+        let state = market.state_mut("figi");
+        state.update_candle(candle(0, 1.0));
+        state.update_candle(candle(0, 2.0));
+        state.update_candle(candle(1, 3.0));
+        assert_eq!(state.candles.iter().map(|c|c.close).collect::<Vec<_>>(), vec![2.0, 3.0]);
+        assert!(!market.candles_stale("figi"));
+
+        market.state_mut("figi").candles_received = Some(SystemTime::now() - Duration::from_secs(60));
+        assert!(market.candles_stale("figi"));
+    }
+
+    fn fx_market() -> Market {
+        let stock = |figi: &str, ticker: &str| Stock { ticker: ticker.to_owned(), ..Stock::unknown(figi) };
+        let mut market = Market::default();
+        market.update_stocks(InstrumentIndex::new(vec![
+            stock("USD", "USD000UTSTOM"),
+            stock("EUR", "EUR_RUB__TOM"),
+            stock("JPY", "JPYRUB_TOM"),
+            stock("AAPL", "AAPL"),
+        ]));
+        market.state_mut("USD").orderbook = Orderbook { bids: vec![(74.0, 1)], asks: vec![(76.0, 1)], ..Default::default() };
+        // иена торгуется за 100 штук
+        market.state_mut("JPY").orderbook = Orderbook { bids: vec![(71.0, 1)], ..Default::default() };
+        market
+    }
+
+    #[test]
+    fn test_rate() {
+        let market = fx_market();
+        assert_eq!(market.rate(Currency::RUB), Some(1.0));
+        assert_eq!(market.rate(Currency::USD), Some(75.0));
+        assert_eq!(market.rate(Currency::JPY), Some(0.71));
+        assert_eq!(market.rate(Currency::EUR), None);
+        assert_eq!(market.rate(Currency::CHF), None);
+    }
+
+    #[test]
+    fn test_portfolio() {
+        let mut market = fx_market();
+        let aapl = Position { lots: 2, balance: 2.0, currency: Some(Currency::USD), average_price: Some(100.0), expected_yield: 10.0 };
+        let usd = Position { lots: 0, balance: 5.0, currency: Some(Currency::RUB), average_price: Some(75.0), expected_yield: 0.0 };
+        market.update_portfolio(
+            vec![("AAPL".to_owned(), aapl), ("USD".to_owned(), usd)],
+            vec![(Currency::RUB, 1000.0), (Currency::USD, 5.0), (Currency::JPY, 1000.0), (Currency::EUR, 10.0), (Currency::CHF, 0.0)],
+            Vec::new(),
+        );
+        let mut fx = market.fx_figis();
+        fx.sort();
+        // без стакана по франку его курс не нужен: франков на счете нет
+        assert_eq!(fx, vec!["EUR", "JPY", "USD"]);
+
+        let portfolio = market.portfolio(&[Currency::RUB, Currency::USD]);
+        // валютная пара в портфеле дублирует остаток валюты и в позиции не попадает
+        assert_eq!(portfolio.positions.iter().map(|(s, _)|s.figi.as_str()).collect::<Vec<_>>(), vec!["AAPL"]);
+        assert_eq!(portfolio.currencies.len(), 4);
+        assert_eq!(portfolio.missing_rates, vec![Currency::EUR]);
+        // 1000 + 5 * 75 + 1000 * 0.71 + (2 * 100 + 10) * 75, евро без курса не учтены
+        let rub = 1000.0 + 375.0 + 710.0 + 15750.0;
+        assert_eq!(portfolio.totals, vec![(Currency::RUB, rub), (Currency::USD, rub / 75.0)]);
+    }
+
+    #[test]
+    fn test_orderbook() {
+        let book = Orderbook {
+            bids: vec![(99.0, 10), (98.5, 20), (97.0, 50)],
+            asks: vec![(101.0, 5), (101.5, 15), (103.0, 100)],
+            ..Default::default()
+        };
+        assert_eq!(book.mid(), Some(100.0));
+        assert_eq!(book.spread(), Some(2.0));
+        assert_eq!(book.spread_bps(), Some(200.0));
+        assert_eq!(book.vwap(OrderKind::Buy, 5), Some(101.0));
+        assert_eq!(book.vwap(OrderKind::Buy, 10), Some(101.25));
+        assert_eq!(book.vwap(OrderKind::Sell, 81), None);
+        assert_eq!(book.depth(OrderKind::Sell, 0, 0.5), 10);
+        assert_eq!(book.depth(OrderKind::Sell, 1, 0.5), 30);
+        assert_eq!(book.depth(OrderKind::Buy, 3, 0.5), 20);
+        assert_eq!(book.imbalance(2), Some(0.2));
+        assert!((book.impact(OrderKind::Sell, 30).unwrap() - 0.04 / 3.0).abs() < 1e-9);
+        assert_eq!(Orderbook::default().imbalance(5), None);
+    }
+}
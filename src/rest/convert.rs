@@ -1,5 +1,12 @@
+use std::convert::{TryFrom, TryInto};
+
 use tinkoff_api::models::*;
-use crate::model::{OrderState, Stock};
+use crate::model::{DateTime, OperationKind, OrderState, Stock, Trade};
+use super::RestError;
+
+fn parse_date(date: &str) -> Result<DateTime, RestError> {
+    DateTime::parse_from_rfc3339(date).map_err(|e|RestError::Decode(format!("{}: {}", date, e)))
+}
 
 impl From<&MarketInstrument> for Stock {
     fn from(i: &MarketInstrument) -> Self {
@@ -16,7 +23,7 @@ impl From<&MarketInstrument> for Stock {
 
 impl From<Order> for OrderState {
     fn from(o: Order) -> Self {
-        let Order { order_id, figi, operation, price, requested_lots, executed_lots, ..} = o;
+        let Order { order_id, figi, operation, status, price, requested_lots, executed_lots, ..} = o;
         let order = crate::model::Order {
             figi,
             kind: operation.into(),
@@ -26,7 +33,116 @@ impl From<Order> for OrderState {
         crate::model::OrderState {
             order_id,
             order,
+            status,
             executed: executed_lots as u32,
         }
     }
 }
+
+impl TryFrom<OperationTrade> for Trade {
+    type Error = RestError;
+
+    fn try_from(t: OperationTrade) -> Result<Self, Self::Error> {
+        Ok(Trade {
+            time: parse_date(&t.date)?,
+            price: t.price,
+            quantity: t.quantity as u32,
+        })
+    }
+}
+
+impl TryFrom<Operation> for crate::model::Operation {
+    type Error = RestError;
+
+    fn try_from(o: Operation) -> Result<Self, Self::Error> {
+        use OperationTypeWithCommission::*;
+        let Operation { date, figi, currency, payment, operation_type, trades, commission, .. } = o;
+        let trades = trades.unwrap_or_default().into_iter().map(TryInto::try_into).collect::<Result<Vec<_>, _>>()?;
+        let trade = |kind| OperationKind::Trade {
+            kind,
+            trades,
+            commission: commission.map(|c|c.value).unwrap_or(0.0),
+        };
+        let kind = match operation_type {
+            Some(Buy) | Some(BuyCard) => trade(OperationType::Buy),
+            Some(Sell) => trade(OperationType::Sell),
+            Some(BrokerCommission) | Some(ExchangeCommission) | Some(ServiceCommission) |
+            Some(MarginCommission) | Some(OtherCommission) => OperationKind::Commission,
+            Some(Dividend) => OperationKind::Dividend,
+            Some(Coupon) => OperationKind::Coupon,
+            Some(Repayment) | Some(PartRepayment) => OperationKind::Repayment,
+            Some(Tax) | Some(TaxLucre) | Some(TaxDividend) | Some(TaxCoupon) | Some(TaxBack) => OperationKind::Tax,
+            Some(PayIn) | Some(PayOut) => OperationKind::Cash,
+            Some(SecurityIn) | Some(SecurityOut) => OperationKind::Transfer,
+            None => OperationKind::Other,
+        };
+        Ok(crate::model::Operation {
+            time: parse_date(&date)?,
+            figi,
+            currency,
+            payment,
+            kind,
+        })
+    }
+}
+
+impl From<TradeStatus> for crate::model::TradeStatus {
+    fn from(s: TradeStatus) -> Self {
+        match s {
+            TradeStatus::NormalTrading => crate::model::TradeStatus::NormalTrading,
+            TradeStatus::NotAvailableForTrading => crate::model::TradeStatus::NotAvailableForTrading,
+        }
+    }
+}
+
+pub fn sandbox_currency(c: Currency) -> SandboxCurrency {
+    match c {
+        Currency::RUB => SandboxCurrency::RUB,
+        Currency::USD => SandboxCurrency::USD,
+        Currency::EUR => SandboxCurrency::EUR,
+        Currency::GBP => SandboxCurrency::GBP,
+        Currency::HKD => SandboxCurrency::HKD,
+        Currency::CHF => SandboxCurrency::CHF,
+        Currency::JPY => SandboxCurrency::JPY,
+        Currency::CNY => SandboxCurrency::CNY,
+        Currency::_TRY => SandboxCurrency::_TRY,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn operation(date: &str, kind: OperationTypeWithCommission) -> Operation {
+        let mut op = Operation::new("1".to_owned(), OperationStatus::Done, Currency::USD, -20.5, false, date.to_owned());
+        op.operation_type = Some(kind);
+        op.figi = Some("figi".to_owned());
+        op.commission = Some(MoneyAmount::new(Currency::USD, -0.5));
+        op.trades = Some(vec![
+            OperationTrade::new("t1".to_owned(), date.to_owned(), 10.0, 1),
+            OperationTrade::new("t2".to_owned(), date.to_owned(), 10.5, 1),
+        ]);
+        op
+    }
+
+    #[test]
+    fn test_operation() {
+        let op = crate::model::Operation::try_from(operation("2021-02-01T10:00:00+03:00", OperationTypeWithCommission::Buy)).unwrap();
+        assert_eq!(op.time.to_rfc3339(), "2021-02-01T10:00:00+03:00");
+        match op.kind {
+            OperationKind::Trade { kind, trades, commission } => {
+                assert_eq!(kind, OperationType::Buy);
+                assert_eq!(trades.iter().map(|t|t.quantity).sum::<u32>(), 2);
+                assert_eq!(commission, -0.5);
+            }
+            kind => panic!("unexpected {:?}", kind),
+        }
+        let op = crate::model::Operation::try_from(operation("2021-02-01T10:00:00Z", OperationTypeWithCommission::TaxDividend)).unwrap();
+        assert_eq!(op.kind, OperationKind::Tax);
+
+        let mut broken = operation("2021-02-01T10:00:00Z", OperationTypeWithCommission::Sell);
+        broken.trades.as_mut().unwrap()[1].date = "вчера".to_owned();
+        assert!(matches!(crate::model::Operation::try_from(broken), Err(RestError::Decode(_))));
+        assert!(crate::model::Operation::try_from(operation("01.02.2021", OperationTypeWithCommission::Coupon)).is_err());
+    }
+}
@@ -1,34 +1,67 @@
 use std::time::SystemTime;
 
-use tinkoff_api::apis::Error;
-use crate::model::{Candle, DateTime, Interval, Order, OrderState, Position, Stock};
+use super::error::Error;
+use crate::model::{Account, Candle, Currency, DateTime, Interval, Operation, Order, OrderState, Orderbook, Position, Stock, TradeStatus};
 
 #[derive(Clone, Debug)]
 pub enum Request {
     Instruments,
     Candles { figi: String, from: DateTime, to: DateTime, interval: Interval},
-    LimitOrder(SystemTime, Order),
+    Orderbook { figi: String, depth: u32 },
+    LimitOrder(Order),
     Portfolio,
+    Operations { from: DateTime, to: DateTime, figi: Option<String> },
+    Accounts,
+    Sandbox(SandboxRequest),
 }
 
-#[derive(Debug)]
-pub enum Response {
-    Err(Request, ErrX),
-    Stocks(Vec<Stock>),
-    Candles { figi: String, candles: Vec<Candle>},
-    Order(SystemTime, OrderState),
-    Portfolio { positions: Vec<(String, Position)>, orders: Vec<OrderState> },
+/// Управление счетом в песочнице
+#[derive(Clone, Debug)]
+pub enum SandboxRequest {
+    Register,
+    SetCurrency { currency: Currency, balance: f64 },
+    SetPosition { figi: String, balance: f64 },
+    Clear,
+    Remove,
 }
 
-#[derive(Debug)]
-pub struct ErrX{
-    msg: String,
+/// Классы приоритета: заявки первыми, опрос портфеля следом, загрузка истории последней
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Orders,
+    Portfolio,
+    History,
 }
 
-impl <T: std::fmt::Debug> From<Error<T>> for ErrX {
-    fn from(e: Error<T>) -> Self {
-        Self {
-            msg: format!("{:?}",e),
+impl Request {
+    pub fn priority(&self) -> Priority {
+        match self {
+            Request::LimitOrder(..) => Priority::Orders,
+            Request::Portfolio | Request::Orderbook {..} | Request::Accounts | Request::Sandbox(..) => Priority::Portfolio,
+            Request::Instruments | Request::Candles {..} | Request::Operations {..} => Priority::History,
         }
     }
-}
\ No newline at end of file
+
+    /// Повтор заявки может выставить ее дважды, поэтому повторяем ее только если брокер точно ее не принял
+    pub fn can_retry(&self, e: &Error) -> bool {
+        match self {
+            Request::LimitOrder(..) | Request::Sandbox(SandboxRequest::Register) => matches!(e, Error::RateLimit),
+            _ => e.is_retryable(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Err(Request, Error),
+    Stocks(Vec<Stock>),
+    Candles { figi: String, candles: Vec<Candle>},
+    Orderbook { figi: String, orderbook: Orderbook, trade_status: TradeStatus },
+    Order(OrderState),
+    OrderRejected(Order, String),
+    Portfolio { time: SystemTime, positions: Vec<(String, Position)>, currencies: Vec<(Currency, f64)>, orders: Vec<OrderState> },
+    Operations(Vec<Operation>),
+    Accounts(Vec<Account>),
+    SandboxDone(SandboxRequest),
+    SandboxRegistered(Account),
+}
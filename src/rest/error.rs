@@ -1,16 +1,95 @@
-use tinkoff_api::{apis::market_api::MarketStocksGetError, models::Error as ServerError};
+use std::fmt::Display;
 
-#[derive(Debug)]
+use reqwest::StatusCode;
+use tinkoff_api::models::Error as ServerError;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    Server(ServerError),
-    UnknownValue(serde_json::Value)
+    Network(String),
+    Timeout,
+    Status(u16, String),
+    Unauthorized,
+    RateLimit,
+    Rejected { code: String, message: String },
+    Decode(String),
+}
+
+impl Error {
+    /// Можно ли повторить запрос без риска: сбой мог произойти до того, как брокер его обработал
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::Timeout | Error::RateLimit => true,
+            Error::Status(status, _) => *status >= 500,
+            Error::Unauthorized | Error::Rejected {..} | Error::Decode(_) => false,
+        }
+    }
+
+    /// Брокер точно не выполнил запрос; при сбое связи или ошибке сервера запрос мог пройти
+    pub fn is_rejection(&self) -> bool {
+        match self {
+            Error::Unauthorized | Error::RateLimit | Error::Rejected {..} => true,
+            Error::Status(status, _) => (400..500).contains(status),
+            Error::Network(_) | Error::Timeout | Error::Decode(_) => false,
+        }
+    }
 }
 
-impl From<MarketStocksGetError> for Error {
-    fn from(e: MarketStocksGetError) -> Self {
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "Нет связи с брокером: {}", e),
+            Error::Timeout => f.write_str("Брокер не ответил вовремя"),
+            Error::Status(status, content) => write!(f, "Брокер ответил {}: {}", status, content),
+            Error::Unauthorized => f.write_str("Токен не подходит"),
+            Error::RateLimit => f.write_str("Слишком много запросов"),
+            Error::Rejected { code, message } => write!(f, "Брокер отказал ({}): {}", code, message),
+            Error::Decode(e) => write!(f, "Непонятный ответ брокера: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<T> From<tinkoff_api::apis::Error<T>> for Error {
+    fn from(e: tinkoff_api::apis::Error<T>) -> Self {
+        use tinkoff_api::apis::Error as ApiError;
         match e {
-            MarketStocksGetError::Status500(e) => Error::Server(e),
-            MarketStocksGetError::UnknownValue(v) => Error::UnknownValue(v)
+            ApiError::Reqwest(e) if e.is_timeout() => Error::Timeout,
+            ApiError::Reqwest(e) if e.is_decode() => Error::Decode(e.to_string()),
+            ApiError::Reqwest(e) => Error::Network(e.to_string()),
+            ApiError::Io(e) => Error::Network(e.to_string()),
+            ApiError::Serde(e) => Error::Decode(e.to_string()),
+            ApiError::ResponseError(response) => match response.status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Error::Unauthorized,
+                StatusCode::TOO_MANY_REQUESTS => Error::RateLimit,
+                status => match serde_json::from_str::<ServerError>(&response.content) {
+                    Ok(ServerError { payload, .. }) if payload.code.is_some() => Error::Rejected {
+                        code: payload.code.unwrap_or_default(),
+                        message: payload.message.unwrap_or_default(),
+                    },
+                    _ => Error::Status(status.as_u16(), response.content),
+                }
+            }
         }
     }
 }
+
+#[test]
+fn test_classification() {
+    use tinkoff_api::apis::{Error as ApiError, ResponseContent};
+    let response = |status: u16, content: &str| -> Error {
+        ApiError::<()>::ResponseError(ResponseContent {
+            status: StatusCode::from_u16(status).unwrap(),
+            content: content.to_owned(),
+            entity: None,
+        }).into()
+    };
+    assert_eq!(response(401, ""), Error::Unauthorized);
+    assert_eq!(response(429, ""), Error::RateLimit);
+    let rejected = response(500, r#"{"trackingId": "1", "status": "Error", "payload": {"message": "Недостаточно средств", "code": "NOT_ENOUGH_BALANCE"}}"#);
+    assert_eq!(rejected, Error::Rejected { code: "NOT_ENOUGH_BALANCE".to_owned(), message: "Недостаточно средств".to_owned() });
+    assert!(!rejected.is_retryable());
+    let unavailable = response(503, "Service Unavailable");
+    assert_eq!(unavailable, Error::Status(503, "Service Unavailable".to_owned()));
+    assert!(unavailable.is_retryable());
+}
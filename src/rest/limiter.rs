@@ -0,0 +1,88 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Группы методов, на которые брокер считает лимиты запросов отдельно
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Group {
+    Market,
+    Orders,
+    LimitOrder,
+    Portfolio,
+    Operations,
+    User,
+    Sandbox,
+}
+
+impl Group {
+    /// Сколько запросов группы можно сделать за минуту
+    fn limit(self) -> usize {
+        match self {
+            Group::Market => 240,
+            Group::Orders => 100,
+            Group::LimitOrder => 100,
+            Group::Portfolio => 120,
+            Group::Operations => 120,
+            Group::User => 120,
+            Group::Sandbox => 120,
+        }
+    }
+}
+
+/// Ограничитель запросов со скользящим окном: каждой группе свое окно,
+/// чтобы опрос портфеля не съедал лимит на выставление заявок
+pub struct Limiter {
+    window: Duration,
+    requests: Mutex<HashMap<Group, VecDeque<Instant>>>,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::with_window(Duration::from_secs(60))
+    }
+
+    pub fn with_window(window: Duration) -> Self {
+        Self { window, requests: Default::default() }
+    }
+
+    /// Ждет, пока в окне группы освободится место, и занимает его
+    pub async fn acquire(&self, group: Group) {
+        while let Some(free_at) = self.try_acquire(group) {
+            log::warn!("rate limit for {:?} reached, waiting", group);
+            tokio::time::sleep_until(free_at).await;
+        }
+    }
+
+    /// Занимает место в окне группы, а если мест нет - возвращает, когда освободится
+    fn try_acquire(&self, group: Group) -> Option<Instant> {
+        let mut requests = self.requests.lock().unwrap();
+        let requests = requests.entry(group).or_default();
+        let now = Instant::now();
+        while matches!(requests.front(), Some(&t) if now.duration_since(t) >= self.window) {
+            requests.pop_front();
+        }
+        match requests.front() {
+            Some(&oldest) if requests.len() >= group.limit() => Some(oldest + self.window),
+            _ => {
+                requests.push_back(now);
+                None
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_limiter() {
+    let window = Duration::from_millis(100);
+    let limiter = Limiter::with_window(window);
+    let start = Instant::now();
+    for _ in 0..Group::Orders.limit() {
+        limiter.acquire(Group::Orders).await;
+    }
+    limiter.acquire(Group::Market).await;
+    assert!(start.elapsed() < window);
+    limiter.acquire(Group::Orders).await;
+    assert!(start.elapsed() >= window);
+}
@@ -1,57 +1,121 @@
 mod convert;
 pub mod entities;
+pub mod error;
+mod limiter;
+mod queue;
 
 use async_channel::{Receiver, Sender};
 use entities::*;
 use tinkoff_api::apis::configuration::Configuration;
 use tinkoff_api::apis::market_api::*;
+use tinkoff_api::apis::operations_api::*;
 use tinkoff_api::apis::orders_api::*;
 use tinkoff_api::apis::portfolio_api::*;
-use tinkoff_api::models::LimitOrderRequest;
+use tinkoff_api::apis::sandbox_api::*;
+use tinkoff_api::apis::user_api::*;
+use tinkoff_api::models::{LimitOrderRequest, OperationStatus, SandboxRegisterRequest, SandboxSetCurrencyBalanceRequest, SandboxSetPositionBalanceRequest};
 use tokio_compat_02::FutureExt;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use limiter::{Group, Limiter};
+use queue::Queue;
 
-use crate::model::{OrderState, Position, ServiceHandle};
-pub use entities::{Request as RestRequest, Response as RestResponse};
+use crate::model::{Account, OrderState, Orderbook, Position, RequestId, ServiceHandle, backoff};
+pub use entities::{Request as RestRequest, Response as RestResponse, SandboxRequest};
+pub use error::Error as RestError;
 
 pub struct Rest;
 
 impl Rest {
-    pub fn start(token: String, uri: String) -> ServiceHandle<Request, Response>{
+    pub fn start(token: String, uri: String, account: Option<String>) -> ServiceHandle<(RequestId, Request), (RequestId, Response)>{
         let (sender, r) = async_channel::bounded(1000);
         let (s, receiver) = async_channel::bounded(1000);
-        start_client(token, uri, receiver, sender);
+        start_client(token, uri, account, receiver, sender);
         ServiceHandle::new(s, r)
     }
 }
 
+/// Настройки клиента и общий для всех запросов ограничитель
+struct Client {
+    conf: Configuration,
+    account: Option<String>,
+    limiter: Limiter,
+}
+
 pub fn start_client(
     token: String,
     root_url: String,
-    receiver: Receiver<Request>,
-    sender: Sender<Response>,
+    account: Option<String>,
+    receiver: Receiver<(RequestId, Request)>,
+    sender: Sender<(RequestId, Response)>,
 ) {
     let conf = Configuration {
         base_path: root_url,
         bearer_access_token: Some(token),
         ..Default::default()
     };
+    let client = Arc::new(Client { conf, account, limiter: Limiter::new() });
+    let done = Arc::new(tokio::sync::Notify::new());
     tokio::spawn(async move {
-        while let Ok(req) = receiver.recv().await {
-            match send(&conf, req.clone()).await {
-                Ok(res) => {sender.send(res).await;},
-                Err(e) => {sender.send(Response::Err(req, e)).await;},
+        let mut queue = Queue::new(WORKERS, WORKERS - 1);
+        let mut open = true;
+        while open || !queue.is_empty() {
+            while let Some((id, req, permit)) = queue.pop() {
+                let (client, sender, done, receiver) = (client.clone(), sender.clone(), done.clone(), receiver.clone());
+                tokio::spawn(async move {
+                    let res = send_with_retry(&client, id, req).await;
+                    if sender.send((id, res)).await.is_err() {
+                        log::info!("{} response dropped: nobody listens, stopping", id);
+                        receiver.close();
+                    }
+                    drop(permit);
+                    done.notify_one();
+                });
+            }
+            tokio::select! {
+                req = receiver.recv(), if open => match req {
+                    Ok((id, req)) => queue.push(id, req),
+                    Err(_) => open = false,
+                },
+                _ = done.notified() => {}
             }
         }
     });
 
 }
 
-async fn send(conf: &Configuration, request: Request) -> Result<Response, ErrX> {
+const WORKERS: usize = 4;
+const MAX_ATTEMPTS: u32 = 5;
+
+async fn send_with_retry(client: &Client, id: RequestId, request: Request) -> Response {
+    let mut attempt = 0;
+    loop {
+        match send(client, request.clone()).await {
+            Ok(res) => return res,
+            Err(e) if attempt + 1 < MAX_ATTEMPTS && request.can_retry(&e) => {
+                let delay = backoff(attempt, Duration::from_millis(500), Duration::from_secs(30));
+                log::warn!("{} {:?} failed: {}, retry in {:?}", id, request, e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Response::Err(request, e),
+        }
+    }
+}
+
+async fn send(client: &Client, request: Request) -> Result<Response, RestError> {
+    let Client { conf, account, limiter } = client;
+    let account = account.as_deref();
     Ok(match request {
         Request::Instruments => {
+            limiter.acquire(Group::Market).await;
             let stocks = market_stocks_get(conf).compat().await?.payload.instruments;
+            limiter.acquire(Group::Market).await;
             let etfs = market_etfs_get(conf).compat().await?.payload.instruments;
+            limiter.acquire(Group::Market).await;
             let bonds = market_bonds_get(conf).compat().await?.payload.instruments;
+            limiter.acquire(Group::Market).await;
             let currencies = market_currencies_get(conf).compat().await?.payload.instruments;
             let instruments = stocks.iter()
                 .chain(etfs.iter())
@@ -60,6 +124,7 @@ async fn send(conf: &Configuration, request: Request) -> Result<Response, ErrX>
             Response::Stocks(instruments.map(Into::into).collect())
         },
         Request::Candles {figi,from,to, interval,} => {
+            limiter.acquire(Group::Market).await;
             let response =
                 market_candles_get_own(&conf, &figi, from.to_rfc3339(), to.to_rfc3339(), "1min")
                     .compat()
@@ -74,8 +139,24 @@ async fn send(conf: &Configuration, request: Request) -> Result<Response, ErrX>
                     .collect(),
             }
         }
-        Request::LimitOrder(key, order) => {
-            let tinkoff_api::models::PlacedLimitOrder { executed_lots, order_id, .. } = orders_limit_order_post(
+        Request::Orderbook { figi, depth } => {
+            limiter.acquire(Group::Market).await;
+            // снимок сделан не раньше отправки запроса: этот момент и считаем временем получения
+            let sent = SystemTime::now();
+            let book = market_orderbook_get(conf, &figi, depth as i32).compat().await?.payload;
+            let level = |o: &tinkoff_api::models::OrderResponse|(o.price, o.quantity as u32);
+            let orderbook = Orderbook {
+                time: chrono::DateTime::<chrono::Utc>::from(sent).into(),
+                received: sent,
+                streamed: false,
+                bids: book.bids.iter().map(level).collect(),
+                asks: book.asks.iter().map(level).collect(),
+            };
+            Response::Orderbook { figi, orderbook, trade_status: book.trade_status.into() }
+        }
+        Request::LimitOrder(order) => {
+            limiter.acquire(Group::LimitOrder).await;
+            let tinkoff_api::models::PlacedLimitOrder { executed_lots, order_id, status, reject_reason, message, .. } = orders_limit_order_post(
                 &conf,
                     &order.figi,
                     LimitOrderRequest {
@@ -83,18 +164,76 @@ async fn send(conf: &Configuration, request: Request) -> Result<Response, ErrX>
                         operation: order.kind,
                         price: order.price,
                     },
-                    None,
+                    account,
                 ).compat().await?.payload;
-            Response::Order(key, OrderState {order_id, order, executed: executed_lots as u32})
+            if let tinkoff_api::models::OrderStatus::Rejected = status {
+                let reason = message.or(reject_reason).unwrap_or_default();
+                return Ok(Response::OrderRejected(order, reason));
+            }
+            Response::Order(OrderState {order_id, order, status, executed: executed_lots as u32})
         }
         Request::Portfolio => {
-            let orders = orders_get(&conf, None).compat().await?.payload.into_iter().map(Into::into).collect();
-            let positions = portfolio_get(&conf, None).compat().await?
+            limiter.acquire(Group::Orders).await;
+            let time = SystemTime::now();
+            let orders = orders_get(&conf, account).compat().await?.payload.into_iter().map(Into::into).collect();
+            limiter.acquire(Group::Portfolio).await;
+            let positions = portfolio_get(&conf, account).compat().await?
             .payload.positions.into_iter().map(|p|{
-                (p.figi, Position {lots: p.lots, balance: p.balance})
+                (p.figi, Position {
+                    lots: p.lots,
+                    balance: p.balance,
+                    currency: p.average_position_price.as_ref().or(p.expected_yield.as_ref()).map(|m|m.currency),
+                    average_price: p.average_position_price.map(|m|m.value),
+                    expected_yield: p.expected_yield.map_or(0.0, |m|m.value),
+                })
             }).collect();
-            Response::Portfolio {positions, orders}
+            limiter.acquire(Group::Portfolio).await;
+            let currencies = portfolio_currencies_get(conf, account).compat().await?
+                .payload.currencies.into_iter().map(|c|(c.currency, c.balance)).collect();
+            Response::Portfolio {time, positions, currencies, orders}
         },
+        Request::Operations { from, to, figi } => {
+            limiter.acquire(Group::Operations).await;
+            let operations = operations_get(conf, from.to_rfc3339(), to.to_rfc3339(), figi.as_deref(), account)
+                .compat().await?.payload.operations;
+            let operations = operations.into_iter()
+                .filter(|o|o.status != OperationStatus::Decline)
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?;
+            Response::Operations(operations)
+        }
+        Request::Accounts => {
+            limiter.acquire(Group::User).await;
+            let accounts = user_accounts_get(conf).compat().await?.payload.accounts;
+            Response::Accounts(accounts.into_iter().map(|a|Account {
+                id: a.broker_account_id,
+                kind: a.broker_account_type,
+            }).collect())
+        }
+        Request::Sandbox(request) => {
+            limiter.acquire(Group::Sandbox).await;
+            match &request {
+                SandboxRequest::Register => {
+                    let broker_account_type = Some(tinkoff_api::models::BrokerAccountType::Tinkoff);
+                    let a = sandbox_register_post(conf, Some(SandboxRegisterRequest { broker_account_type }))
+                        .compat().await?.payload;
+                    return Ok(Response::SandboxRegistered(Account { id: a.broker_account_id, kind: a.broker_account_type }));
+                }
+                SandboxRequest::SetCurrency { currency, balance } => {
+                    let currency = convert::sandbox_currency(*currency);
+                    sandbox_currencies_balance_post(conf, SandboxSetCurrencyBalanceRequest { currency, balance: *balance }, account)
+                        .compat().await?;
+                }
+                SandboxRequest::SetPosition { figi, balance } => {
+                    let figi = Some(figi.clone());
+                    sandbox_positions_balance_post(conf, SandboxSetPositionBalanceRequest { figi, balance: *balance }, account)
+                        .compat().await?;
+                }
+                SandboxRequest::Clear => { sandbox_clear_post(conf, account).compat().await?; }
+                SandboxRequest::Remove => { sandbox_remove_post(conf, account).compat().await?; }
+            }
+            Response::SandboxDone(request)
+        }
     })
 }
 
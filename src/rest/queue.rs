@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::model::RequestId;
+use super::entities::{Priority, Request};
+
+/// Очередь запросов по классам приоритета. Всем запросам, кроме заявок, достается не больше
+/// `background` исполнителей: исполнитель держится и во время повторов и ожидания лимита,
+/// поэтому заявкам всегда должно быть кому уйти
+pub struct Queue {
+    requests: BTreeMap<Priority, VecDeque<(RequestId, Request)>>,
+    workers: Arc<Semaphore>,
+    background: Arc<Semaphore>,
+}
+
+pub struct Permit {
+    _worker: OwnedSemaphorePermit,
+    _background: Option<OwnedSemaphorePermit>,
+}
+
+impl Queue {
+    pub fn new(workers: usize, background: usize) -> Self {
+        Self {
+            requests: BTreeMap::new(),
+            workers: Arc::new(Semaphore::new(workers)),
+            background: Arc::new(Semaphore::new(background)),
+        }
+    }
+
+    pub fn push(&mut self, id: RequestId, request: Request) {
+        self.requests.entry(request.priority()).or_default().push_back((id, request));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.values().all(VecDeque::is_empty)
+    }
+
+    /// Самый приоритетный запрос, который можно запустить прямо сейчас, вместе с занятым исполнителем
+    pub fn pop(&mut self) -> Option<(RequestId, Request, Permit)> {
+        let worker = self.workers.clone().try_acquire_owned().ok()?;
+        let (&priority, queue) = self.requests.iter_mut().find(|(_, q)|!q.is_empty())?;
+        let background = match priority {
+            Priority::Orders => None,
+            _ => Some(self.background.clone().try_acquire_owned().ok()?),
+        };
+        let (id, request) = queue.pop_front()?;
+        Some((id, request, Permit { _worker: worker, _background: background }))
+    }
+}
+
+#[test]
+fn test_queue() {
+    use crate::model::{Order, OrderKind};
+    let order = Order { figi: "figi".to_owned(), kind: OrderKind::Buy, price: 1.0, quantity: 1 };
+    let mut queue = Queue::new(2, 1);
+    queue.push(RequestId::new(), Request::Instruments);
+    queue.push(RequestId::new(), Request::Instruments);
+    queue.push(RequestId::new(), Request::Portfolio);
+    queue.push(RequestId::new(), Request::LimitOrder(order));
+    let (_, first, _first) = queue.pop().unwrap();
+    assert!(matches!(first, Request::LimitOrder(..)));
+    let (_, second, second_permit) = queue.pop().unwrap();
+    assert!(matches!(second, Request::Portfolio));
+    assert!(queue.pop().is_none());
+    drop(second_permit);
+    let (_, third, _third) = queue.pop().unwrap();
+    assert!(matches!(third, Request::Instruments));
+    assert!(queue.pop().is_none());
+
+    // опрос портфеля занимает не всех исполнителей, заявка уходит сразу
+    let mut queue = Queue::new(2, 1);
+    queue.push(RequestId::new(), Request::Portfolio);
+    queue.push(RequestId::new(), Request::Portfolio);
+    let _portfolio = queue.pop().unwrap();
+    assert!(queue.pop().is_none());
+    let order = Order { figi: "figi".to_owned(), kind: OrderKind::Sell, price: 1.0, quantity: 1 };
+    queue.push(RequestId::new(), Request::LimitOrder(order));
+    assert!(matches!(queue.pop().unwrap().1, Request::LimitOrder(..)));
+}
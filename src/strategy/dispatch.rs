@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use enum_dispatch::enum_dispatch;
+use super::filtered::Filtered;
 use super::fixed_amount::FixedAmount;
 use super::trailing_stop::TrailingStop;
 use super::Strategy;
@@ -13,6 +14,7 @@ use strum::EnumIter;
 pub enum StrategyKind {
     FixedAmount,
     TrailingStop,
+    Filtered,
 }
 
 impl StrategyKind {
@@ -0,0 +1,276 @@
+use chrono::{Datelike, FixedOffset, NaiveTime, Timelike, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::model::{Fill, Market, Order, OrderState, TradeStatus};
+use super::{ConfigError, Decision, Strategy, StrategyKind};
+
+/// Обертка над любой стратегией: пропускает к ней рынок только если выполнены все ограничения
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Filtered {
+    strategy: Option<Box<StrategyKind>>,
+    hours_from: Option<u32>,
+    hours_to: Option<u32>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    max_spread: Option<f64>,
+    max_trades: Option<u32>,
+    cooldown: Option<i64>,
+    #[serde(default)]
+    auctions: bool,
+    trades_day: i32,
+    trades_today: u32,
+    last_order: i64,
+}
+
+fn moscow_now() -> chrono::DateTime<FixedOffset> {
+    Utc::now().with_timezone(&FixedOffset::east_opt(3*3600).expect("valid offset"))
+}
+
+fn parse_minutes(value: &str) -> Result<u32, ConfigError> {
+    let time = NaiveTime::parse_from_str(value, "%H:%M")?;
+    Ok(time.hour() * 60 + time.minute())
+}
+
+impl Filtered {
+    fn in_hours(&self, minutes: u32) -> bool {
+        match (self.hours_from, self.hours_to) {
+            (Some(from), Some(to)) if from <= to => minutes >= from && minutes < to,
+            (Some(from), Some(to)) => minutes >= from || minutes < to,
+            (Some(from), None) => minutes >= from,
+            (None, Some(to)) => minutes < to,
+            (None, None) => true,
+        }
+    }
+
+    fn price_allowed(&self, market: &Market, figi: &str) -> bool {
+        let orderbook = match market.state(figi) {
+            Some(state) => &state.orderbook,
+            None => return false,
+        };
+        let (bid, ask) = match (orderbook.bids.first(), orderbook.asks.first()) {
+            (Some(&(bid, _)), Some(&(ask, _))) => (bid, ask),
+            _ => return false,
+        };
+        if matches!(self.min_price, Some(min) if bid < min) || matches!(self.max_price, Some(max) if ask > max) {
+            return false;
+        }
+        !matches!(self.max_spread, Some(spread) if (ask - bid) / bid > spread)
+    }
+
+    fn allowed(&mut self, market: &Market, strategy: &StrategyKind) -> bool {
+        let now = moscow_now();
+        if now.num_days_from_ce() != self.trades_day {
+            self.trades_day = now.num_days_from_ce();
+            self.trades_today = 0;
+        }
+        if !self.in_hours(now.hour() * 60 + now.minute()) {
+            return false;
+        }
+        if matches!(self.max_trades, Some(max) if self.trades_today >= max) {
+            return false;
+        }
+        if matches!(self.cooldown, Some(secs) if now.timestamp() - self.last_order < secs) {
+            return false;
+        }
+        strategy.figis().iter().all(|figi| self.price_allowed(market, figi))
+    }
+}
+
+impl Strategy for Filtered {
+    fn name(&self) -> &'static str {
+        "С ограничениями"
+    }
+
+    fn description(&self) -> &'static str {
+        r#"Запускает выбранную стратегию только при выполнении ограничений:
+        торговые часы, диапазон цены, максимальный спред, лимит сделок в день и пауза после заявки"#
+    }
+
+    fn params(&self) -> Vec<(&'static str, &'static str)> {
+        let mut params = vec![
+            ("strategy", "Название стратегии, которую ограничиваем"),
+            ("hours_from", "(10:00) с какого времени торговать, МСК"),
+            ("hours_to", "(18:40) до какого времени торговать, МСК"),
+            ("min_price", "Не торговать, если цена ниже"),
+            ("max_price", "Не торговать, если цена выше"),
+            ("max_spread", "(0.005 - 0.5%) не торговать, если спред больше"),
+            ("max_trades", "Сколько заявок можно выставить за день"),
+            ("cooldown", "Пауза после заявки, секунд"),
+            ("auctions", "(true/false) торговать ли на аукционах открытия и закрытия"),
+        ];
+        if let Some(strategy) = &self.strategy {
+            params.extend(strategy.params());
+        }
+        params
+    }
+
+    fn configure(&mut self, key: &str, value: String) -> Result<(), ConfigError> {
+        match key {
+            "strategy" => {
+                let strategy = StrategyKind::variants().remove(value.trim()).ok_or(ConfigError::STRATEGY_NOT_FOUND)?;
+                self.strategy = Some(Box::new(strategy));
+            }
+            "hours_from" => self.hours_from = Some(parse_minutes(&value)?),
+            "hours_to" => self.hours_to = Some(parse_minutes(&value)?),
+            "min_price" => self.min_price = Some(value.parse()?),
+            "max_price" => self.max_price = Some(value.parse()?),
+            "max_spread" => self.max_spread = Some(value.parse()?),
+            "max_trades" => self.max_trades = Some(value.parse()?),
+            "cooldown" => self.cooldown = Some(value.parse()?),
+            "auctions" => self.auctions = value.trim().parse()?,
+            _ => match &mut self.strategy {
+                Some(strategy) => strategy.configure(key, value)?,
+                None => return Err(ConfigError::INVALID_PARAM),
+            }
+        }
+        Ok(())
+    }
+
+    fn figis(&self) -> Vec<String> {
+        self.strategy.as_ref().map(|s|s.figis()).unwrap_or_default()
+    }
+
+    fn trade_statuses(&self) -> Vec<TradeStatus> {
+        let mut statuses = self.strategy.as_ref().map(|s|s.trade_statuses()).unwrap_or_default();
+        if self.auctions {
+            statuses.extend_from_slice(&[
+                TradeStatus::OpeningPeriod,
+                TradeStatus::DiscreteAuction,
+                TradeStatus::ClosingAuction,
+                TradeStatus::TradingAtClosingAuctionPrice,
+            ]);
+        }
+        statuses
+    }
+
+    fn make_decision(&mut self, market: &Market) -> Vec<Decision> {
+        let mut strategy = match self.strategy.take() {
+            Some(strategy) => strategy,
+            None => return Vec::new(),
+        };
+        let decisions = if self.allowed(market, &strategy) {
+            strategy.make_decision(market)
+        } else {
+            Vec::new()
+        };
+        self.strategy = Some(strategy);
+        decisions
+    }
+
+    fn balance(&self) -> f64 {
+        self.strategy.as_ref().map(|s|s.balance()).unwrap_or(0.0)
+    }
+
+    /// Лимит и паузу тратят только принятые брокером заявки
+    fn on_accepted(&mut self, order: &OrderState) {
+        self.trades_today += 1;
+        self.last_order = moscow_now().timestamp();
+        if let Some(s) = &mut self.strategy { s.on_accepted(order) }
+    }
+
+    fn on_partially_filled(&mut self, order: &OrderState, fill: Fill) {
+        if let Some(s) = &mut self.strategy { s.on_partially_filled(order, fill) }
+    }
+
+    fn on_filled(&mut self, order: &OrderState, fill: Fill) {
+        if let Some(s) = &mut self.strategy { s.on_filled(order, fill) }
+    }
+
+    fn on_cancelled(&mut self, order: &OrderState) {
+        if let Some(s) = &mut self.strategy { s.on_cancelled(order) }
+    }
+
+    fn on_rejected(&mut self, order: &Order, reason: &str) {
+        if let Some(s) = &mut self.strategy { s.on_rejected(order, reason) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{OrderStatus, Orderbook};
+
+    fn make_market(bid: f64, ask: f64) -> Market {
+        let mut market = Market::default();
+        market.state_mut("figi").orderbook = Orderbook {
+            bids: vec![(bid, 10)],
+            asks: vec![(ask, 10)],
+            ..Default::default()
+        };
+        market
+    }
+
+    fn make_filtered() -> Filtered {
+        let mut filtered = Filtered::default();
+        filtered.configure("strategy", "Скользящий стоп".to_owned()).unwrap();
+        filtered.configure("figi", "figi".to_owned()).unwrap();
+        filtered.configure("quantity", "1".to_owned()).unwrap();
+        filtered
+    }
+
+    #[test]
+    fn test_guards() {
+        let mut filtered = make_filtered();
+        filtered.configure("max_spread", "0.01".to_owned()).unwrap();
+        filtered.configure("max_trades", "1".to_owned()).unwrap();
+        assert!(filtered.allowed(&make_market(100.0, 100.5), &filtered.strategy.clone().unwrap()));
+        assert!(!filtered.allowed(&make_market(100.0, 102.0), &filtered.strategy.clone().unwrap()));
+        filtered.configure("max_price", "50".to_owned()).unwrap();
+        assert!(!filtered.allowed(&make_market(100.0, 100.5), &filtered.strategy.clone().unwrap()));
+
+        let mut filtered = make_filtered();
+        filtered.configure("max_trades", "1".to_owned()).unwrap();
+        filtered.make_decision(&make_market(100.0, 100.5));
+        let order = match filtered.make_decision(&make_market(90.0, 90.5)).pop() {
+            Some(Decision::Order(order)) => order,
+            _ => panic!("no order"),
+        };
+        // отклоненная заявка лимит не тратит
+        filtered.on_rejected(&order, "not enough balance");
+        assert!(filtered.allowed(&make_market(100.0, 100.5), &filtered.strategy.clone().unwrap()));
+        filtered.on_accepted(&OrderState { order_id: "1".to_owned(), order, status: OrderStatus::New, executed: 0 });
+        assert!(!filtered.allowed(&make_market(100.0, 100.5), &filtered.strategy.clone().unwrap()));
+    }
+
+    #[test]
+    fn test_hours() {
+        let mut filtered = make_filtered();
+        filtered.configure("hours_from", "10:00".to_owned()).unwrap();
+        filtered.configure("hours_to", "18:40".to_owned()).unwrap();
+        assert!(!filtered.in_hours(9 * 60 + 59));
+        assert!(filtered.in_hours(10 * 60));
+        assert!(filtered.in_hours(18 * 60 + 39));
+        assert!(!filtered.in_hours(18 * 60 + 40));
+
+        // окно через полночь
+        filtered.configure("hours_from", "23:00".to_owned()).unwrap();
+        filtered.configure("hours_to", "02:00".to_owned()).unwrap();
+        assert!(filtered.in_hours(23 * 60 + 30));
+        assert!(filtered.in_hours(60));
+        assert!(!filtered.in_hours(2 * 60));
+        assert!(!filtered.in_hours(12 * 60));
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut filtered = make_filtered();
+        filtered.configure("cooldown", "60".to_owned()).unwrap();
+        let market = make_market(100.0, 100.5);
+        let strategy = filtered.strategy.clone().unwrap();
+        assert!(filtered.allowed(&market, &strategy));
+
+        let order = Order { figi: "figi".to_owned(), kind: crate::model::OrderKind::Sell, price: 100.0, quantity: 1 };
+        filtered.on_accepted(&OrderState { order_id: "1".to_owned(), order, status: OrderStatus::New, executed: 0 });
+        assert!(!filtered.allowed(&market, &strategy));
+        filtered.last_order -= 61;
+        assert!(filtered.allowed(&market, &strategy));
+    }
+
+    #[test]
+    fn test_serde() {
+        let strategy = StrategyKind::Filtered(make_filtered());
+        let json = serde_json::to_string(&strategy).unwrap();
+        let des: StrategyKind = serde_json::from_str(&json).unwrap();
+        assert_eq!(strategy, des);
+    }
+}
@@ -1,6 +1,6 @@
 use super::*;
 use serde::{Serialize, Deserialize};
-use crate::model::StockState;
+use crate::model::{Fill, OrderState, Orderbook, StockState};
 use crate::model::OrderKind;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -14,6 +14,12 @@ pub struct FixedAmount {
     corrected_sell: f64,
     factor: f64,
     first_buy: bool,
+    /// На сколько шагов цены заявка может уйти от лучшей цены; `None` - заявка на весь объем без оглядки на стакан
+    #[serde(default)]
+    max_ticks: Option<u32>,
+    /// Размер лота из данных биржи: позиция считается в штуках, заявки и исполнения - в лотах
+    #[serde(skip)]
+    lot: Option<u32>,
 }
 
 impl Default for FixedAmount {
@@ -34,59 +40,90 @@ impl FixedAmount {
             corrected_sell: 0.01,
             factor: 1.0,
             first_buy: true,
+            max_ticks: None,
+            lot: None,
         }
     }
 
-    fn _make_decision(&mut self, figi: String, bid_price: f64, ask_price: f64, balance: f64) -> Vec<Decision> {
+    /// С `max_ticks` заявка не больше объема в пределах стольких шагов от лучшей цены, чтобы исполниться сразу;
+    /// остаток доберется следующими заявками
+    fn _make_decision(&mut self, figi: String, orderbook: &Orderbook, tick: f64, balance: f64) -> Vec<Decision> {
         let target = self.target;
-        let factor = self.factor;
+        let lot = self.lot.unwrap_or(1);
+        let (bid_price, ask_price) = match (orderbook.best(OrderKind::Sell), orderbook.best(OrderKind::Buy)) {
+            (Some(bid), Some(ask)) => (bid.0, ask.0),
+            _ => return Vec::new(),
+        };
+        let max_ticks = self.max_ticks;
+        let slippage = tick * max_ticks.unwrap_or(0) as f64;
+        let available = |kind, quantity: u32| match max_ticks {
+            Some(ticks) => quantity.min(orderbook.depth(kind, ticks, tick)),
+            None => quantity,
+        };
 
         let over = balance * bid_price - target;
         if over/target > self.corrected_sell { //TODO: использовать threshold
-            let quantity = (over/bid_price)as u32;
+            let quantity = available(OrderKind::Sell, (over/bid_price) as u32 / lot);
             if quantity == 0 {
                 return Vec::new();
             }
-            log::info!("over: {:.2}, sell", over);
-            self.balance += (quantity as f64) * bid_price;
-            self.corrected_buy /= factor;
-            if self.corrected_buy < self.buy_threshold {
-                self.corrected_buy = self.buy_threshold;
-            }
-            self.corrected_sell *= factor;
+            log::info!("over: {:.2}, sell {}; {}", over, quantity, liquidity(orderbook, OrderKind::Sell, quantity));
             return vec![Decision::Order(Order {
                 kind: OrderKind::Sell,
                 figi, 
-                price: bid_price, 
+                price: bid_price - slippage, 
                 quantity,
             })];
         }
         let under = target - balance * ask_price;
         if under/target > self.corrected_buy {
-            let quantity = (under/bid_price) as u32;
+            let quantity = available(OrderKind::Buy, (under/bid_price) as u32 / lot);
             if quantity == 0 {
                 return Vec::new();
             }
-            log::info!("under: {:.2}, buy", under);
-            self.balance -= (quantity as f64) * ask_price;
-            self.corrected_sell /= factor;
-            if self.corrected_sell < self.sell_threshold {
-                self.corrected_sell = self.sell_threshold
-            }
-            self.corrected_buy *= factor;
-            if self.first_buy {
-                self.balance = 0.0;
-                self.first_buy = false;
-            }
+            log::info!("under: {:.2}, buy {}; {}", under, quantity, liquidity(orderbook, OrderKind::Buy, quantity));
             return vec![Decision::Order(Order {
                 kind: OrderKind::Buy,
                 figi, 
-                price: ask_price, 
+                price: ask_price + slippage, 
                 quantity,
             })];
         }
         Vec::new()
     }
+
+    /// Пороги сдвигаются только после исполнения: отклоненная или снятая заявка их не меняет
+    fn correct_thresholds(&mut self, kind: OrderKind) {
+        let factor = self.factor;
+        match kind {
+            OrderKind::Sell => {
+                self.corrected_buy = (self.corrected_buy / factor).max(self.buy_threshold);
+                self.corrected_sell *= factor;
+            }
+            OrderKind::Buy => {
+                self.corrected_sell = (self.corrected_sell / factor).max(self.sell_threshold);
+                self.corrected_buy *= factor;
+            }
+        }
+    }
+
+    /// Баланс считается по цене заявки, а не сделок: для покупки это оценка сверху, для продажи - снизу
+    fn on_fill(&mut self, order: &OrderState, fill: Fill) {
+        if self.first_buy && order.order.kind == OrderKind::Buy {
+            return;
+        }
+        let amount = fill.limit_price * (fill.quantity * self.lot.unwrap_or(1)) as f64;
+        match order.order.kind {
+            OrderKind::Buy => self.balance -= amount,
+            OrderKind::Sell => self.balance += amount,
+        }
+    }
+}
+
+/// Состояние стакана для лога решения
+fn liquidity(orderbook: &Orderbook, kind: OrderKind, quantity: u32) -> String {
+    format!("vwap {:?}, impact {:?}, spread {:?} bps, imbalance {:?}",
+        orderbook.vwap(kind, quantity), orderbook.impact(kind, quantity), orderbook.spread_bps(), orderbook.imbalance(5))
 }
 
 fn have_orders(stock: &StockState)  -> bool {
@@ -99,18 +136,33 @@ impl Strategy for FixedAmount {
             if have_orders(stock) {
                 return Vec::new();
             }
+            let info = market.stock(&self.figi);
+            self.lot = info.map(|s|s.lot);
+            let tick = info.map_or(0.0, |s|s.min_increment);
             let vol =  stock.position.balance;
-            let orderbook = &stock.orderbook;
-            if let (Some(&bid), Some(&ask)) = (orderbook.bids.get(0), orderbook.asks.get(0)) {
-                return self._make_decision(self.figi.clone(), bid.0, ask.0, vol)
-            }
+            return self._make_decision(self.figi.clone(), &stock.orderbook, tick, vol)
         }
         Vec::new()
     }
+    fn figis(&self) -> Vec<String> {
+        vec![self.figi.clone()]
+    }
     fn balance(&self) -> f64 {
         self.balance/self.target * 100.0
     }
 
+    fn on_partially_filled(&mut self, order: &OrderState, fill: Fill) {
+        self.on_fill(order, fill);
+    }
+
+    fn on_filled(&mut self, order: &OrderState, fill: Fill) {
+        self.on_fill(order, fill);
+        self.correct_thresholds(order.order.kind);
+        if order.order.kind == OrderKind::Buy {
+            self.first_buy = false;
+        }
+    }
+
     fn name(&self) -> &'static str {
         "Фикс стоимость"
     }
@@ -127,6 +179,7 @@ impl Strategy for FixedAmount {
             ("buy_threshold", "Порог снижения суммы для покупки"),
             ("sell_threshold", "Порог роста цены для продажи"),
             ("factor", ""),
+            ("max_ticks", "На сколько шагов цены можно уйти от лучшей цены ради объема (по умолчанию объем стакана не учитывается)"),
         }
     }
 
@@ -143,9 +196,33 @@ impl Strategy for FixedAmount {
                 self.corrected_sell = self.sell_threshold;
             }
             "factor" => self.factor = value.parse()?,
+            "max_ticks" => self.max_ticks = Some(value.parse()?),
             _ => return Err(ConfigError::INVALID_PARAM),
         }
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::OrderStatus;
+
+    #[test]
+    fn test_thresholds() {
+        let mut strategy = FixedAmount { factor: 2.0, ..FixedAmount::new("figi".to_owned()) };
+        let orderbook = Orderbook { bids: vec![(99.0, 100)], asks: vec![(100.0, 100)], ..Default::default() };
+        let order = match strategy._make_decision("figi".to_owned(), &orderbook, 0.01, 0.0).pop() {
+            Some(Decision::Order(order)) => order,
+            _ => panic!("no order"),
+        };
+        assert_eq!((order.kind, order.quantity), (OrderKind::Buy, 101));
+
+        strategy.on_rejected(&order, "not enough balance");
+        assert_eq!((strategy.corrected_buy, strategy.corrected_sell), (0.01, 0.01));
+
+        let state = OrderState { order_id: "1".to_owned(), order: order.clone(), status: OrderStatus::Fill, executed: order.quantity };
+        strategy.on_filled(&state, Fill { limit_price: order.price, quantity: order.quantity });
+        assert_eq!((strategy.corrected_buy, strategy.corrected_sell), (0.02, 0.01));
+    }
+}
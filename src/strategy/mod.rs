@@ -1,9 +1,11 @@
 mod dispatch;
+mod filtered;
 mod fixed_amount;
 mod trailing_stop;
-use crate::model::{Market, Order};
+use crate::model::{Fill, Market, Order, OrderState, TradeStatus};
 use enum_dispatch::enum_dispatch;
 pub use dispatch::StrategyKind;
+use filtered::Filtered;
 use fixed_amount::FixedAmount;
 use trailing_stop::TrailingStop;
 
@@ -17,8 +19,23 @@ pub trait Strategy {
     fn description(&self) -> &'static str;
     fn params(&self) -> Vec<(&'static str, &'static str)>;
     fn configure(&mut self, key: &str, value: String) -> Result<(), ConfigError>;
+    fn figis(&self) -> Vec<String>;
     fn make_decision(&mut self, market: &Market) -> Vec<Decision>;
     fn balance(&self) -> f64;
+    /// В каких торговых режимах инструмента стратегии можно принимать решения
+    fn trade_statuses(&self) -> Vec<TradeStatus> {
+        vec![TradeStatus::NormalTrading]
+    }
+    /// Заявка принята биржей
+    fn on_accepted(&mut self, _order: &OrderState) {}
+    /// Заявка исполнена частично, `fill` - только что исполненная часть
+    fn on_partially_filled(&mut self, _order: &OrderState, _fill: Fill) {}
+    /// Заявка исполнена полностью, `fill` - последняя исполненная часть
+    fn on_filled(&mut self, _order: &OrderState, _fill: Fill) {}
+    /// Заявка снята, неисполненный остаток больше не в работе
+    fn on_cancelled(&mut self, _order: &OrderState) {}
+    /// Заявку не приняли
+    fn on_rejected(&mut self, _order: &Order, _reason: &str) {}
 }
 
 #[derive(Default, Clone)]
@@ -38,6 +55,9 @@ impl Strategy for Dummy {
     fn configure(&mut self, _key: &str, _value: String) -> Result<(), ConfigError> {
         Ok(())
     }
+    fn figis(&self) -> Vec<String> {
+        Vec::new()
+    }
     fn make_decision(&mut self, _market: &Market) -> Vec<Decision> {
         Vec::new()
     }
@@ -49,7 +69,7 @@ impl Strategy for Dummy {
 
 pub use error::ConfigError;
 mod error {
-    use std::{error::Error, fmt::Display, num::{ParseFloatError, ParseIntError}};
+    use std::{error::Error, fmt::Display, num::{ParseFloatError, ParseIntError}, str::ParseBoolError};
 
     #[derive(Debug)]
     pub struct  ConfigError(&'static str);
@@ -62,6 +82,7 @@ mod error {
     impl ConfigError {
         pub const INVALID_PARAM: ConfigError= ConfigError("Нет такого параметра");
         pub const TICKER_NOT_FOUND: ConfigError = ConfigError("Бумага с таким тикером не найдена");
+        pub const STRATEGY_NOT_FOUND: ConfigError = ConfigError("Нет такой стратегии");
     }
 
     impl From<ParseFloatError> for ConfigError {
@@ -75,6 +96,18 @@ mod error {
             Self("Не-не, нужно целое число")
         }
     }
+
+    impl From<ParseBoolError> for ConfigError {
+        fn from(_: ParseBoolError) -> Self {
+            Self("Не-не, нужно true или false")
+        }
+    }
+
+    impl From<chrono::ParseError> for ConfigError {
+        fn from(_: chrono::ParseError) -> Self {
+            Self("Не-не, нужно время в формате ЧЧ:ММ")
+        }
+    }
 }
 
 
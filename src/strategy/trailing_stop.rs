@@ -1,9 +1,15 @@
 
+use std::time::{Duration, SystemTime};
+
 use serde::{Serialize, Deserialize};
 
-use crate::model::{Order, OrderKind};
+use crate::model::{Order, OrderKind, OrderState};
 use super::{ConfigError, Decision, Strategy};
 
+/// Сколько раз выставлять отклоненную заявку заново, прежде чем остановиться
+const MAX_REJECTIONS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TrailingStop {
     figi: String, 
@@ -11,6 +17,11 @@ pub struct TrailingStop {
     best_price: f64,
     quantity: usize,
     finished: bool,
+    #[serde(skip)]
+    rejections: u32,
+    /// До этого момента после отклонения заявку не повторяем
+    #[serde(skip)]
+    retry_at: Option<SystemTime>,
 }
 
 impl TrailingStop {
@@ -32,6 +43,8 @@ impl Default for TrailingStop {
             best_price: 0.0,
             quantity: 0,
             finished: false,
+            rejections: 0,
+            retry_at: None,
         }
     }
 }
@@ -63,8 +76,13 @@ impl Strategy for TrailingStop {
         Ok(())
     }
 
+    fn figis(&self) -> Vec<String> {
+        vec![self.figi.clone()]
+    }
+
     fn make_decision(&mut self, market: &crate::model::Market) -> Vec<Decision> {
         if self.finished { return Vec::new() }
+        if matches!(self.retry_at, Some(at) if at > SystemTime::now()) { return Vec::new() }
         if let Some(state) = market.state(&self.figi) {
             match state.orderbook.bids.get(0).map(|(p, _)|*p).unwrap_or(self.best_price) {
                 price if price > self.best_price => self.best_price = price,
@@ -81,4 +99,47 @@ impl Strategy for TrailingStop {
     fn balance(&self) -> f64 {
         0.0
     }
-}
\ No newline at end of file
+
+    fn on_cancelled(&mut self, order: &OrderState) {
+        self.quantity -= (order.executed as usize).min(self.quantity);
+        self.finished = self.quantity == 0;
+    }
+
+    /// Постоянная ошибка брокера (например, не хватает бумаг) не должна превращаться в бесконечный поток заявок
+    fn on_rejected(&mut self, _order: &Order, reason: &str) {
+        self.rejections += 1;
+        if self.rejections >= MAX_REJECTIONS {
+            log::warn!("trailing stop {} stopped after {} rejections: {}", self.figi, self.rejections, reason);
+            return;
+        }
+        self.finished = false;
+        self.retry_at = Some(SystemTime::now() + RETRY_DELAY);
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Market;
+
+    #[test]
+    fn test_rejections() {
+        let mut market = Market::default();
+        let mut stop = TrailingStop { figi: "figi".to_owned(), best_price: 100.0, quantity: 1, ..Default::default() };
+        market.state_mut("figi").orderbook.bids = vec![(90.0, 1)];
+        assert_eq!(stop.make_decision(&market).len(), 1);
+        let order = stop.make_order(90.0);
+
+        stop.on_rejected(&order, "not enough balance");
+        // повтор только после паузы
+        assert!(stop.make_decision(&market).is_empty());
+        stop.retry_at = None;
+        assert_eq!(stop.make_decision(&market).len(), 1);
+
+        stop.on_rejected(&order, "not enough balance");
+        stop.retry_at = None;
+        assert_eq!(stop.make_decision(&market).len(), 1);
+        stop.on_rejected(&order, "not enough balance");
+        stop.retry_at = None;
+        assert!(stop.make_decision(&market).is_empty());
+    }
+}
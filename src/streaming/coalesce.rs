@@ -0,0 +1,154 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use super::entities::{Event, Response, ResponseType};
+
+/// Место события в очереди. Замененный стакан переезжает в конец, чтобы не обогнать события соединения
+enum Slot {
+    Event(Event),
+    Orderbook(String),
+}
+
+#[derive(Default)]
+struct Queue {
+    slots: VecDeque<Slot>,
+    orderbooks: HashMap<String, Event>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+/// Очередь событий, в которой из стаканов по одному инструменту остается только последний
+pub fn channel() -> (Sender, Batches) {
+    let shared = Arc::new(Shared::default());
+    (Sender { shared: shared.clone() }, Batches { shared })
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+
+    /// `false`, если читатель уже закрыт
+    pub fn push(&self, event: Event) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        let mut queue = self.shared.queue.lock().unwrap();
+        match &event {
+            Event::Data(Response { kind: ResponseType::Orderbook { figi, .. }, .. }) => {
+                let figi = figi.clone();
+                if queue.orderbooks.insert(figi.clone(), event).is_some() {
+                    queue.slots.retain(|slot|!matches!(slot, Slot::Orderbook(f) if *f == figi));
+                }
+                queue.slots.push_back(Slot::Orderbook(figi));
+            }
+            _ => queue.slots.push_back(Slot::Event(event)),
+        }
+        drop(queue);
+        self.shared.notify.notify_one();
+        true
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Читатель забирает все накопившиеся события разом
+pub struct Batches {
+    shared: Arc<Shared>,
+}
+
+impl Batches {
+    /// Ждет хотя бы одно событие; `None`, когда писатель закрыт и очередь пуста
+    pub async fn recv(&self) -> Option<Vec<Event>> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if !queue.slots.is_empty() {
+                    let Queue { slots, orderbooks, .. } = &mut *queue;
+                    let batch = slots.drain(..).filter_map(|slot|match slot {
+                        Slot::Event(event) => Some(event),
+                        Slot::Orderbook(figi) => orderbooks.remove(&figi),
+                    }).collect();
+                    return Some(batch);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn book(figi: &str, price: f64) -> Event {
+        Event::Data(serde_json::from_str(&format!(r#"{{
+            "event": "orderbook", "time": "2019-08-07T15:35:00Z",
+            "payload": {{"figi": "{}", "depth": 1, "bids": [[{}, 1]], "asks": []}}
+        }}"#, figi, price)).unwrap())
+    }
+
+    fn bid(event: &Event) -> f64 {
+        match event {
+            Event::Data(Response { kind: ResponseType::Orderbook { bids, .. }, .. }) => bids[0].0,
+            _ => 0.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let (sender, batches) = channel();
+        for price in 1..=1000 {
+            assert!(sender.push(book("A", price as f64)));
+        }
+        sender.push(Event::Connected(0));
+        sender.push(book("B", 1.0));
+        sender.push(book("A", 2000.0));
+
+        let batch = batches.recv().await.unwrap();
+        assert_eq!(batch.len(), 3);
+        assert!(matches!(batch[0], Event::Connected(0)));
+        assert_eq!(batch[1].figi(), Some("B"));
+        assert_eq!((batch[2].figi(), bid(&batch[2])), (Some("A"), 2000.0));
+
+        sender.push(book("A", 1.0));
+        drop(sender);
+        assert_eq!(batches.recv().await.unwrap().len(), 1);
+        assert!(batches.recv().await.is_none());
+
+        // стакан после переподключения приходит позже события о нем
+        let (sender, batches) = channel();
+        sender.push(book("A", 1.0));
+        sender.push(Event::Disconnected(0));
+        sender.push(Event::Connected(0));
+        sender.push(book("A", 2.0));
+        let batch = batches.recv().await.unwrap();
+        assert_eq!(batch.len(), 3);
+        assert!(matches!(batch[0], Event::Disconnected(0)));
+        assert!(matches!(batch[1], Event::Connected(0)));
+        assert_eq!(bid(&batch[2]), 2.0);
+
+        let (sender, batches) = channel();
+        drop(batches);
+        assert!(!sender.push(Event::Connected(0)));
+    }
+}
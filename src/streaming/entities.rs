@@ -3,6 +3,8 @@ use std::str::FromStr;
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, FixedOffset};
 
+use crate::model::RequestId;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
 pub enum Interval {
     #[serde(rename="1min")]
@@ -49,20 +51,110 @@ pub enum Request {
     InfoUnsubsribe {figi: String},
 }
 
+impl Request {
+    /// Подписка, которую запрос создает или отменяет, и создает ли
+    pub fn subscription(&self) -> (Request, bool) {
+        use Request::*;
+        match self.clone() {
+            req @ CandleSubscribe { .. } | req @ OrderbookSubscribe { .. } | req @ InfoSubscribe { .. } => (req, true),
+            CandleUnsubscribe { figi, interval } => (CandleSubscribe { figi, interval }, false),
+            OrderbookUnsubscribe { figi, depth } => (OrderbookSubscribe { figi, depth }, false),
+            InfoUnsubsribe { figi } => (InfoSubscribe { figi }, false),
+        }
+    }
+
+    /// Запрос, отменяющий эту подписку
+    pub fn unsubscribe(&self) -> Request {
+        use Request::*;
+        match self.subscription().0 {
+            CandleSubscribe { figi, interval } => CandleUnsubscribe { figi, interval },
+            OrderbookSubscribe { figi, depth } => OrderbookUnsubscribe { figi, depth },
+            InfoSubscribe { figi } => InfoUnsubsribe { figi },
+            req => req,
+        }
+    }
+}
+
+/// Запрос вместе с идентификатором: брокер вернет его в ошибке по этому запросу
+#[derive(Serialize)]
+pub struct Tagged<'a> {
+    #[serde(flatten)]
+    pub request: &'a Request,
+    pub request_id: String,
+}
+
 impl ToString for Request {
     fn to_string(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
 }
+/// Что стриминг сообщает трейдеру
+#[derive(Debug, Clone)]
+pub enum Event {
+    Data(Response),
+    /// Брокер отверг подписку; `request` - подписка, если ее удалось найти по идентификатору
+    Error { request: Option<(RequestId, Request)>, error: String },
+    /// Соединение с этим номером установлено
+    Connected(usize),
+    Disconnected(usize),
+    /// Соединение больше не нужно и закрыто
+    Closed(usize),
+    /// После переподключения соединение заново отправило столько подписок
+    Resubscribed(usize, usize),
+}
+
+/// Вид события для фильтров подписчиков
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Candle,
+    Orderbook,
+    Info,
+    Error,
+    Connection,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Data(Response { kind: ResponseType::Candle(_), .. }) => EventKind::Candle,
+            Event::Data(Response { kind: ResponseType::Orderbook { .. }, .. }) => EventKind::Orderbook,
+            Event::Data(Response { kind: ResponseType::Info { .. }, .. }) => EventKind::Info,
+            Event::Data(Response { kind: ResponseType::Error { .. }, .. }) | Event::Error { .. } => EventKind::Error,
+            Event::Connected(_) | Event::Disconnected(_) | Event::Closed(_) | Event::Resubscribed(..) => EventKind::Connection,
+        }
+    }
+
+    /// Инструмент, к которому относится событие
+    pub fn figi(&self) -> Option<&str> {
+        match self {
+            Event::Data(Response { kind: ResponseType::Candle(c), .. }) => Some(&c.figi),
+            Event::Data(Response { kind: ResponseType::Orderbook { figi, .. }, .. }) |
+            Event::Data(Response { kind: ResponseType::Info { figi, .. }, .. }) => Some(figi),
+            Event::Error { request: Some((_, request)), .. } => match request {
+                Request::CandleSubscribe { figi, .. } | Request::CandleUnsubscribe { figi, .. } |
+                Request::OrderbookSubscribe { figi, .. } | Request::OrderbookUnsubscribe { figi, .. } |
+                Request::InfoSubscribe { figi } | Request::InfoUnsubsribe { figi } => Some(figi),
+            },
+            _ => None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Candle {
     o: f64, c: f64, h: f64, l: f64, v: i32, 
     #[serde(with = "rfc3339")]
     time: DateTime<FixedOffset>, 
-    interval: Interval, figi: String
+    interval: Interval, pub figi: String
+}
+
+impl From<Candle> for crate::model::Candle {
+    fn from(candle: Candle) -> Self {
+        Self { open: candle.o, close: candle.c, low: candle.l, high: candle.h, volume: candle.v, time: candle.time }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum TradeStatus {
     BreakInTrading,
@@ -99,6 +191,18 @@ pub enum ResponseType {
         error: String,
     }
 }
+impl ResponseType {
+    /// Подписка, по которой пришло событие
+    pub fn subscription(&self) -> Option<Request> {
+        Some(match self {
+            ResponseType::Candle(c) => Request::CandleSubscribe { figi: c.figi.clone(), interval: c.interval.clone() },
+            ResponseType::Orderbook { figi, depth, .. } => Request::OrderbookSubscribe { figi: figi.clone(), depth: *depth },
+            ResponseType::Info { figi, .. } => Request::InfoSubscribe { figi: figi.clone() },
+            ResponseType::Error { .. } => return None,
+        })
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Response {
     #[serde(with = "rfc3339")]
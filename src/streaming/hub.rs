@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender, TrySendError};
+
+use super::coalesce::{self, Batches};
+use super::entities::{Event, EventKind};
+
+/// Какие события нужны подписчику; `None` пропускает все
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub figis: Option<HashSet<String>>,
+    pub kinds: Option<HashSet<EventKind>>,
+}
+
+impl Filter {
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// События соединения без инструмента проходят фильтр по инструментам
+    fn matches(&self, event: &Event) -> bool {
+        let figi_ok = match (&self.figis, event.figi()) {
+            (Some(figis), Some(figi)) => figis.contains(figi),
+            (Some(_), None) => event.kind() == EventKind::Connection,
+            (None, _) => true,
+        };
+        figi_ok && self.kinds.as_ref().is_none_or(|kinds|kinds.contains(&event.kind()))
+    }
+}
+
+enum Output {
+    /// Если подписчик не успевает, новые события теряются
+    Channel(Sender<Event>),
+    /// Из стаканов по инструменту подписчик получает только последний
+    Latest(coalesce::Sender),
+}
+
+impl Output {
+    fn is_closed(&self) -> bool {
+        match self {
+            Output::Channel(sender) => sender.is_closed(),
+            Output::Latest(sender) => sender.is_closed(),
+        }
+    }
+}
+
+struct Subscriber {
+    filter: Filter,
+    output: Output,
+}
+
+/// Раздает события стриминга подписчикам. Публикация не ждет медленных подписчиков
+#[derive(Clone, Default)]
+pub struct Hub {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Event> {
+        let (sender, receiver) = async_channel::bounded(capacity);
+        self.add(filter, Output::Channel(sender));
+        receiver
+    }
+
+    /// Подписка без потерь, кроме устаревших стаканов: события забираются пачками
+    pub fn subscribe_latest(&self, filter: Filter) -> Batches {
+        let (sender, batches) = coalesce::channel();
+        self.add(filter, Output::Latest(sender));
+        batches
+    }
+
+    fn add(&self, filter: Filter, output: Output) {
+        self.subscribers.lock().unwrap().push(Subscriber { filter, output });
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|s| {
+            if !s.filter.matches(&event) {
+                return !s.output.is_closed();
+            }
+            match &s.output {
+                Output::Channel(sender) => match sender.try_send(event.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        log::warn!("streaming subscriber is too slow, event dropped");
+                        true
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                },
+                Output::Latest(sender) => sender.push(event.clone()),
+            }
+        });
+    }
+}
+
+/// Раз в минуту пишет в лог, сколько событий каждого вида пришло; завершается вместе со стримингом
+pub fn spawn_stats(hub: &Hub) {
+    let kinds = [EventKind::Candle, EventKind::Orderbook, EventKind::Info, EventKind::Error];
+    let receiver = hub.subscribe(Filter { figis: None, kinds: Some(kinds.iter().copied().collect()) }, 1000);
+    tokio::spawn(async move {
+        let mut counts: HashMap<EventKind, usize> = HashMap::new();
+        let mut timer = tokio::time::interval(Duration::from_secs(60));
+        timer.tick().await;
+        loop {
+            tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => *counts.entry(event.kind()).or_default() += 1,
+                    Err(_) => break,
+                },
+                _ = timer.tick() => {
+                    log::info!("streaming events per minute: {:?}", counts);
+                    counts.clear();
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hub() {
+        let hub = Hub::default();
+        let all = hub.subscribe_latest(Filter::all());
+        let filter = Filter {
+            figis: Some(vec!["A".to_owned()].into_iter().collect()),
+            kinds: Some(vec![EventKind::Orderbook].into_iter().collect()),
+        };
+        let books = hub.subscribe(filter, 1);
+        let closed = hub.subscribe(Filter::all(), 10);
+        drop(closed);
+
+        let book = |figi: &str| Event::Data(serde_json::from_str(&format!(r#"{{
+            "event": "orderbook", "time": "2019-08-07T15:35:00Z",
+            "payload": {{"figi": "{}", "depth": 1, "bids": [[1.0, 1]], "asks": [[2.0, 1]]}}
+        }}"#, figi)).unwrap());
+        hub.publish(book("A"));
+        hub.publish(book("B"));
+        hub.publish(Event::Connected(0));
+        // второй стакан по A не влезает в очередь медленного подписчика и теряется, а в пачке заменяет первый
+        hub.publish(book("A"));
+
+        assert_eq!(all.recv().await.unwrap().len(), 3);
+        assert_eq!(books.len(), 1);
+        assert_eq!(books.recv().await.unwrap().figi(), Some("A"));
+        assert_eq!(hub.subscribers.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,145 @@
+//! Локальный websocket-сервер, изображающий стриминг Тинькофф для тестов
+
+use std::collections::HashSet;
+
+use async_channel::{Receiver, Sender};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{WebSocketStream, tungstenite};
+use tungstenite::Message;
+
+/// Инструмент, подписка на который всегда заканчивается ошибкой
+pub const UNKNOWN_FIGI: &str = "NOT_FOUND";
+const TIME: &str = "2019-08-07T15:35:00.029721253Z";
+
+pub enum Command {
+    /// Отправить текущему клиенту сообщение как есть
+    Send(String),
+    /// Оборвать текущее соединение без закрытия
+    Disconnect,
+    /// Перестать читать соединение: пинги остаются без ответа
+    Freeze,
+}
+
+pub struct MockServer {
+    pub uri: String,
+    /// Все запросы клиентов в порядке получения
+    pub requests: Receiver<Value>,
+    /// Сколько раз клиенты подключались
+    pub connections: Receiver<usize>,
+    commands: Sender<Command>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}", listener.local_addr().unwrap());
+        let (requests_sender, requests) = async_channel::unbounded();
+        let (connections_sender, connections) = async_channel::unbounded();
+        let (commands, commands_receiver) = async_channel::unbounded();
+        tokio::spawn(async move {
+            let mut count = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                let websocket = match tokio_tungstenite::accept_async(stream).await {
+                    Ok(websocket) => websocket,
+                    Err(_) => continue,
+                };
+                count += 1;
+                connections_sender.send(count).await.unwrap_or(());
+                // прежний клиент к этому моменту уже отключился, команды получает новый
+                tokio::spawn(serve(websocket, requests_sender.clone(), commands_receiver.clone()));
+            }
+        });
+        Self { uri, requests, connections, commands }
+    }
+
+    pub async fn command(&self, command: Command) {
+        self.commands.send(command).await.unwrap();
+    }
+}
+
+async fn serve(mut websocket: WebSocketStream<TcpStream>, requests: Sender<Value>, commands: Receiver<Command>) {
+    let mut subscriptions = HashSet::new();
+    loop {
+        tokio::select! {
+            msg = websocket.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let request: Value = serde_json::from_str(&text).unwrap();
+                    for reply in on_request(&mut subscriptions, &request) {
+                        if websocket.send(Message::Text(reply.to_string())).await.is_err() {
+                            return;
+                        }
+                    }
+                    requests.send(request).await.unwrap_or(());
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // на пинги tungstenite отвечает сам
+                Some(Ok(_)) => {}
+            },
+            command = commands.recv() => match command {
+                Ok(Command::Send(text)) => websocket.send(Message::Text(text)).await.unwrap_or(()),
+                Ok(Command::Disconnect) | Err(_) => return,
+                Ok(Command::Freeze) => break,
+            },
+        }
+    }
+    // держим соединение открытым, но больше его не читаем и не забираем команды у следующих клиентов
+    drop(commands);
+    futures_util::future::pending::<()>().await;
+}
+
+/// Ответы на запрос по протоколу стриминга: при подписке сразу приходит текущее значение
+fn on_request(subscriptions: &mut HashSet<String>, request: &Value) -> Vec<Value> {
+    let event = request["event"].as_str().unwrap_or_default();
+    let figi = request["figi"].as_str().unwrap_or_default();
+    let (kind, action) = match event.split_once(':') {
+        Some(pair) => pair,
+        None => return vec![error(request, format!("Unknown event {}", event))],
+    };
+    if figi == UNKNOWN_FIGI {
+        return vec![error(request, format!("Subscription {}. FIGI {} not found", event, figi))];
+    }
+    let key = format!("{}:{}", kind, figi);
+    match action {
+        "subscribe" => {
+            subscriptions.insert(key);
+            match kind {
+                "orderbook" => vec![orderbook(figi, request["depth"].as_u64().unwrap_or(1), 100.0)],
+                "candle" => vec![candle(figi, request["interval"].as_str().unwrap_or("1min"))],
+                "instrument_info" => vec![info(figi)],
+                _ => vec![error(request, format!("Unknown event {}", event))],
+            }
+        }
+        "unsubscribe" => {
+            if subscriptions.remove(&key) {
+                Vec::new()
+            } else {
+                vec![error(request, format!("Subscription {} not found", key))]
+            }
+        }
+        _ => vec![error(request, format!("Unknown event {}", event))],
+    }
+}
+
+fn error(request: &Value, error: String) -> Value {
+    json!({ "event": "error", "time": TIME, "payload": { "request_id": request["request_id"], "error": error } })
+}
+
+pub fn orderbook(figi: &str, depth: u64, price: f64) -> Value {
+    json!({ "event": "orderbook", "time": TIME, "payload": {
+        "figi": figi, "depth": depth, "bids": [[price - 0.5, 10]], "asks": [[price + 0.5, 10]]
+    }})
+}
+
+fn candle(figi: &str, interval: &str) -> Value {
+    json!({ "event": "candle", "time": TIME, "payload": {
+        "o": 1.0, "c": 1.0, "h": 1.0, "l": 1.0, "v": 1, "time": TIME, "interval": interval, "figi": figi
+    }})
+}
+
+fn info(figi: &str) -> Value {
+    json!({ "event": "instrument_info", "time": TIME, "payload": {
+        "figi": figi, "trade_status": "normal_trading", "min_price_increment": 0.01, "lot": 1
+    }})
+}
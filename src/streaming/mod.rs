@@ -1,17 +1,30 @@
 
 pub mod entities;
-use std::{collections::HashSet, str::FromStr};
+pub mod hub;
+mod coalesce;
+mod router;
+mod subscriptions;
+#[cfg(test)]
+mod mock;
+use std::str::FromStr;
 use futures_util::{SinkExt, StreamExt};
 
 use tokio::net::TcpStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
 use tungstenite::{Message, http};
 use async_channel::{Sender, Receiver};
-use entities::{Request, Response};
+use entities::{Event, EventKind, Request, Response, ResponseType, Tagged};
+use coalesce::Batches;
+use hub::{Filter, Hub};
+use router::Router;
+use subscriptions::Subscriptions;
 
-use crate::model::ServiceHandle;
+use std::time::Duration;
 
-pub use entities::{Request as StreamingRequest, Response as StreamingResponse};
+use crate::model::{ChannelStopped, RequestId, backoff};
+
+pub use hub::{Hub as StreamingHub, spawn_stats as spawn_streaming_stats};
+pub use entities::{Event as StreamingEvent, Request as StreamingRequest, Response as StreamingResponse};
 
 async fn connect(uri: &str, token: &str) ->  Result<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Error> {
 
@@ -26,113 +39,433 @@ async fn connect(uri: &str, token: &str) ->  Result<WebSocketStream<MaybeTlsStre
     Ok(websocket)
 }
 
+/// Сколько подписок брокер допускает на одном соединении
+const SUBSCRIPTIONS_PER_CONNECTION: usize = 300;
+/// Как часто проверять соединение пингом; если понг не пришел до следующего пинга, соединение разрывается
+const PING_INTERVAL: Duration = Duration::from_secs(17);
+
+/// Запросы к стримингу и события для трейдера
+pub struct StreamingHandle {
+    sender: Sender<(RequestId, Request)>,
+    events: Batches,
+}
+
+impl StreamingHandle {
+    pub async fn send(&self, msg: (RequestId, Request)) -> Result<(), ChannelStopped> {
+        self.sender.send(msg).await.map_err(|_|ChannelStopped)
+    }
+    /// Все события, накопившиеся с прошлого вызова; из стаканов по инструменту - только последний
+    pub async fn recv(&self) -> Result<Vec<Event>, ChannelStopped> {
+        self.events.recv().await.ok_or(ChannelStopped)
+    }
+}
+
+/// Пул соединений: подписки распределяются по соединениям, ответы всех соединений раздаются через хаб
 pub struct Streaming {
+    token: String,
+    uri: String,
+    router: Router,
+    connections: Vec<Sender<(RequestId, Request)>>,
+    hub: Hub,
+    receiver: Receiver<(RequestId, Request)>,
+    errors: Batches,
+}
+
+impl Streaming {
+    /// Трейдер получает события пачками без потерь, кроме устаревших стаканов; остальные подписываются через `hub`
+    pub fn start(token: String, uri: String, hub: Hub) -> StreamingHandle {
+        let events = hub.subscribe_latest(Filter::all());
+        let (s, receiver) = async_channel::bounded(100);
+        let router = Router::new(SUBSCRIPTIONS_PER_CONNECTION);
+        let errors = hub.subscribe_latest(Filter { figis: None, kinds: Some(std::iter::once(EventKind::Error).collect()) });
+        let mut streaming = Self {token, uri, router, connections: Vec::new(), hub, receiver, errors};
+        // первое соединение открываем сразу, чтобы трейдер узнал о подключении
+        streaming.connection(0);
+        tokio::spawn(streaming.run());
+        StreamingHandle { sender: s, events }
+    }
+    async fn run(mut self) {
+        log::info!("Streaming service started");
+        loop {
+            tokio::select! {
+                req = self.receiver.recv() => match req {
+                    Ok((id, req)) => self.on_request(id, req).await,
+                    Err(_) => break,
+                },
+                Some(events) = self.errors.recv() => {
+                    for event in events {
+                        if let Event::Error { request: Some((_, req)), .. } = event {
+                            self.router.release(&req);
+                        }
+                    }
+                    self.close_unused();
+                }
+            }
+        }
+        log::info!("Streaming service stopped");
+    }
+    async fn on_request(&mut self, id: RequestId, req: Request) {
+        match self.router.route(&req) {
+            Some(shard) => {
+                if self.connection(shard).send((id, req)).await.is_err() {
+                    log::error!("connection {} stopped", shard);
+                }
+            }
+            None => log::warn!("{} {:?}: not subscribed", id, req),
+        }
+        self.close_unused();
+    }
+    /// Соединение закрывается, когда у него не остается получателя запросов
+    fn close_unused(&mut self) {
+        while self.connections.len() > self.router.shards() {
+            self.connections.pop();
+            let shard = self.connections.len();
+            log::info!("closing unused streaming connection {}", shard);
+            self.hub.publish(Event::Closed(shard));
+        }
+    }
+    /// Соединение с этим номером; недостающие соединения открываются
+    fn connection(&mut self, shard: usize) -> &Sender<(RequestId, Request)> {
+        while self.connections.len() <= shard {
+            let shard = self.connections.len();
+            log::info!("opening streaming connection {}", shard);
+            let connection = Connection::start(shard, self.token.clone(), self.uri.clone(), self.hub.clone(), PING_INTERVAL);
+            self.connections.push(connection);
+        }
+        &self.connections[shard]
+    }
+}
+
+/// Одно соединение со своими подписками: переподключается и переподписывается само
+struct Connection {
+    shard: usize,
     token: String,
     uri: String,
     need_pong: bool,
+    ping_interval: Duration,
     timer: tokio::time::Interval,
-    state: HashSet<Request>,
-    sender: Sender<Response>,
-    receiver: Receiver<Request>,
-    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    subscriptions: Subscriptions,
+    hub: Hub,
+    receiver: Receiver<(RequestId, Request)>,
+    /// `None`, пока соединения нет
+    websocket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    /// Подписчики уже знают, что соединения нет
+    reported_down: bool,
 }
 
-fn create_timer() -> tokio::time::Interval {
-    tokio::time::interval(std::time::Duration::from_secs(17))
+fn create_timer(period: Duration) -> tokio::time::Interval {
+    tokio::time::interval(period)
 }
 
-impl Streaming {
-    pub fn start(token: String, uri: String) -> ServiceHandle<Request, Response> {
-        let (sender,r) = async_channel::bounded(100);
+impl Connection {
+    fn start(shard: usize, token: String, uri: String, hub: Hub, ping_interval: Duration) -> Sender<(RequestId, Request)> {
         let (s, receiver) = async_channel::bounded(100);
         tokio::spawn (async move {
-            match connect(&uri, &token).await {
-                Ok(websocket) => {
-                    let timer = create_timer();
-                    Self {token, uri, need_pong: false, state: HashSet::new(), sender, receiver, websocket, timer}.run().await
-                }
-                Err(e) => {
-                    log::error!("Some error on websocket connecting: {:?}", e)
-                }
+            let timer = create_timer(ping_interval);
+            let connection = Self {shard, token, uri, need_pong: false, ping_interval, subscriptions: Default::default(), hub, receiver, websocket: None, reported_down: false, timer};
+            if connection.run().await.is_err() {
+                log::info!("streaming connection {} stopped", shard);
             }
         });
-        ServiceHandle::new( s,  r)
+        s
     }
-    async fn run(mut self) {
-        log::info!("Streaming service started");
-        loop {tokio::select! {
-            Some(msg) = self.websocket.next() => self.on_response(msg).await,
-            req = self.receiver.recv() => match req {
-                Ok(req) => {self.on_command(req).await;}
-                Err(_) => break,
-            },
-            _ = self.timer.tick() => self.on_timer().await,
-        }}
+    async fn run(mut self) -> Result<(), ChannelStopped> {
+        loop {
+            let websocket = match self.websocket.as_mut() {
+                Some(websocket) => websocket,
+                None => {
+                    self.reconnect().await?;
+                    continue;
+                }
+            };
+            tokio::select! {
+                msg = websocket.next() => match msg {
+                    Some(msg) => self.on_response(msg).await,
+                    None => self.disconnect("websocket closed").await,
+                },
+                req = self.receiver.recv() => {
+                    let (id, req) = req?;
+                    self.on_command(id, req).await;
+                }
+                _ = self.timer.tick() => self.on_timer().await,
+            }
+        }
     }
     async fn on_response(&mut self, msg: Result<Message, tungstenite::error::Error>) {
          match msg {
             Ok(Message::Text(text)) => match Response::from_str(&text) {
-                Ok(msg) => {self.sender.send(msg).await;},
+                Ok(msg) => self.on_message(msg),
                 Err(e) => log::error!("error on parsing text: {} \n {:?}", text, e),
             },
-            Ok(Message::Ping(data)) => {self.websocket.send(Message::Pong(data)).await;},
+            Ok(Message::Ping(data)) => {
+                if let Err(e) = self.send(Message::Pong(data)).await {
+                    self.disconnect(&format!("cannot send Pong: {:?}", e)).await;
+                }
+            },
             Ok(Message::Pong(_)) => self.need_pong = false,
-            Ok(Message::Close(_)) => self.reconnect().await,
+            Ok(Message::Close(_)) => self.disconnect("websocket closed by server").await,
             Ok(Message::Binary(_)) => {},
-            Err(e) => {
-                log::warn!("error read from websocket: {:?}, reconnecting...", e);
-                self.reconnect().await;
-            }
+            Err(e) => self.disconnect(&format!("error read from websocket: {:?}", e)).await,
         }
     }
-    async fn on_command(&mut self, req: Request) -> Result<(), tungstenite::error::Error>{
-        use Request::*;
-        match req.clone() {   
-            CandleSubscribe { .. } | 
-            InfoSubscribe { .. } |
-            OrderbookSubscribe{ .. } => { self.state.insert(req.clone()); }
-            CandleUnsubscribe { figi, interval } => { self.state.remove( &CandleSubscribe { figi, interval } ); }
-            OrderbookUnsubscribe { figi, depth } => { self.state.remove( &OrderbookSubscribe { figi, depth } ); }
-            InfoUnsubsribe { figi } => { self.state.remove( &InfoSubscribe { figi } ); }
+    fn on_message(&mut self, msg: Response) {
+        let event = match &msg.kind {
+            ResponseType::Error { request_id, error } if request_id.as_deref().is_some_and(|id|self.subscriptions.is_unsubscribe(id)) => {
+                log::debug!("unsubscribe {:?} failed: {}", request_id, error);
+                return;
+            }
+            ResponseType::Error { request_id, error } => {
+                let request = request_id.as_deref().and_then(|id|self.subscriptions.on_error(id, error));
+                match &request {
+                    Some((id, req)) => log::warn!("{} {:?} failed: {}", id, req, error),
+                    None => log::warn!("streaming error {:?}: {}", request_id, error),
+                }
+                Event::Error { request, error: error.clone() }
+            }
+            kind => {
+                self.subscriptions.on_data(kind);
+                Event::Data(msg)
+            }
+        };
+        self.hub.publish(event);
+    }
+    async fn on_command(&mut self, id: RequestId, req: Request) {
+        log::info!("{} {:?}", id, req);
+        self.subscriptions.on_command(id, &req);
+        if let Err(e) = self.send(Tagged { request: &req, request_id: id.to_string() }.into()).await {
+            // подписка уже учтена и уйдет при переподписке
+            self.disconnect(&format!("cannot send {}: {:?}", id, e)).await;
         }
-        self.websocket.send((&req).into()).await
     }
     async fn on_timer(&mut self) {
         if self.need_pong {
-            log::warn!("pong not received, reconnecting...");
-            self.reconnect().await;
+            self.disconnect("pong not received").await;
             return;
         }
-        match self.websocket.send(Message::Ping(vec![])).await {
+        match self.send(Message::Ping(vec![])).await {
             Ok(_) => self.need_pong = true,
-            Err(e) => {
-                log::warn!("cannot send Ping: {:?}, reconnecting...", e);
-                self.reconnect().await;
-            }
+            Err(e) => self.disconnect(&format!("cannot send Ping: {:?}", e)).await,
         }
     }
-    async fn resubscribe(&mut self) -> Result<(), tungstenite::error::Error> {
-        for r in &self.state {
-            self.websocket.send(r.into()).await?;
+    async fn send(&mut self, msg: Message) -> Result<(), tungstenite::error::Error> {
+        match self.websocket.as_mut() {
+            Some(websocket) => websocket.send(msg).await,
+            None => Err(tungstenite::error::Error::AlreadyClosed),
         }
-        Ok(())
     }
-    async fn reconnect(&mut self) {
-        self.websocket.send(Message::Close(None)).await.unwrap_or(());
+    async fn resubscribe(&mut self) -> Result<usize, tungstenite::error::Error> {
+        self.subscriptions.reset();
+        let requests: Vec<_> = self.subscriptions.to_resubscribe()
+            .map(|(request, id)|Tagged { request, request_id: id.to_string() }.into())
+            .collect();
+        let count = requests.len();
+        for msg in requests {
+            self.send(msg).await?;
+        }
+        Ok(count)
+    }
+    async fn disconnect(&mut self, reason: &str) {
+        log::warn!("connection {}: {}, reconnecting...", self.shard, reason);
+        if let Some(mut websocket) = self.websocket.take() {
+            websocket.send(Message::Close(None)).await.unwrap_or(());
+        }
+        self.report_down();
+    }
+    fn report_down(&mut self) {
+        // закрытое пулом соединение уже не нужно никому
+        if !self.reported_down && !self.receiver.is_closed() {
+            self.reported_down = true;
+            self.hub.publish(Event::Disconnected(self.shard));
+        }
+    }
+    fn postpone(&mut self, id: RequestId, req: Request) {
+        log::info!("{} {:?} postponed until connected", id, req);
+        self.subscriptions.on_command(id, &req);
+    }
+    /// Подключается с задержкой между попытками. Запросы тем временем только запоминаются
+    async fn reconnect(&mut self) -> Result<(), ChannelStopped> {
+        let mut attempt = 0;
+        let (uri, token) = (self.uri.clone(), self.token.clone());
         loop {
-            match connect(&self.uri, &self.token).await {
-                Ok(ws) => {
-                    self.websocket = ws;
-                    self.timer = create_timer();
+            let connecting = connect(&uri, &token);
+            tokio::pin!(connecting);
+            let result = loop {
+                tokio::select! {
+                    result = &mut connecting => break result,
+                    req = self.receiver.recv() => {
+                        let (id, req) = req?;
+                        self.postpone(id, req);
+                    }
+                }
+            };
+            match result {
+                Ok(websocket) => {
+                    self.websocket = Some(websocket);
+                    self.timer = create_timer(self.ping_interval);
                     self.need_pong = false;
+                    self.reported_down = false;
+                    self.hub.publish(Event::Connected(self.shard));
                     match self.resubscribe().await {
-                        Ok(_) => break,
-                        Err(e) => log::error!("cannoct resubscribe: {:?}", e),
+                        Ok(0) => return Ok(()),
+                        Ok(count) => {
+                            self.hub.publish(Event::Resubscribed(self.shard, count));
+                            return Ok(())
+                        }
+                        Err(e) => self.disconnect(&format!("cannot resubscribe: {:?}", e)).await,
                     }
                 }
-                Err(e) => log::error!("cannot reconnect: {:?}", e),
+                Err(e) => {
+                    log::error!("connection {}: cannot connect: {:?}", self.shard, e);
+                    // о недоступности при первом подключении тоже сообщаем
+                    self.report_down();
+                }
             }
-            tokio::time::sleep(std::time::Duration::from_secs(61)).await;
+            let delay = backoff(attempt, Duration::from_secs(1), Duration::from_secs(60));
+            attempt += 1;
+            log::info!("connection {}: next attempt in {:?}", self.shard, delay);
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    req = self.receiver.recv() => {
+                        let (id, req) = req?;
+                        self.postpone(id, req);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use entities::Interval;
+    use mock::{Command, MockServer, UNKNOWN_FIGI};
+
+    async fn start(server: &MockServer, ping_interval: Duration) -> (Sender<(RequestId, Request)>, Receiver<Event>) {
+        start_at(server.uri.clone(), ping_interval)
+    }
+
+    fn start_at(uri: String, ping_interval: Duration) -> (Sender<(RequestId, Request)>, Receiver<Event>) {
+        let hub = Hub::default();
+        let events = hub.subscribe(Filter::all(), 100);
+        let connection = Connection::start(0, "token".to_owned(), uri, hub, ping_interval);
+        (connection, events)
+    }
+
+    async fn next<T>(receiver: &Receiver<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.expect("timeout").unwrap()
+    }
+
+    fn orderbook(figi: &str) -> Request {
+        Request::OrderbookSubscribe { figi: figi.to_owned(), depth: 10 }
+    }
+
+    fn data_figi(event: Event) -> String {
+        match event {
+            Event::Data(Response { kind: ResponseType::Orderbook { figi, .. }, .. }) => figi,
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe() {
+        let server = MockServer::start().await;
+        let (connection, events) = start(&server, PING_INTERVAL).await;
+        assert!(matches!(next(&events).await, Event::Connected(0)));
+        assert_eq!(next(&server.connections).await, 1);
+
+        connection.send((RequestId::from_str("req-1").unwrap(), orderbook("A"))).await.unwrap();
+        connection.send((RequestId::from_str("req-2").unwrap(), Request::InfoSubscribe { figi: "B".to_owned() })).await.unwrap();
+        connection.send((RequestId::from_str("req-3").unwrap(), Request::InfoUnsubsribe { figi: "B".to_owned() })).await.unwrap();
+        assert_eq!(data_figi(next(&events).await), "A");
+        assert!(matches!(next(&events).await.kind(), entities::EventKind::Info));
+        assert_eq!(next(&server.requests).await["request_id"], "req-1");
+        next(&server.requests).await;
+        next(&server.requests).await;
+
+        server.command(Command::Disconnect).await;
+        assert!(matches!(next(&events).await, Event::Disconnected(0)));
+        assert!(matches!(next(&events).await, Event::Connected(0)));
+        assert_eq!(next(&server.connections).await, 2);
+        // отмененная подписка не восстанавливается
+        assert!(matches!(next(&events).await, Event::Resubscribed(0, 1)));
+        let request = next(&server.requests).await;
+        assert_eq!((request["event"].as_str(), request["figi"].as_str()), (Some("orderbook:subscribe"), Some("A")));
+        assert_eq!(data_figi(next(&events).await), "A");
+        assert!(server.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_pong() {
+        let server = MockServer::start().await;
+        let (connection, events) = start(&server, Duration::from_millis(200)).await;
+        assert!(matches!(next(&events).await, Event::Connected(0)));
+        connection.send((RequestId::from_str("req-1").unwrap(), orderbook("A"))).await.unwrap();
+        assert_eq!(data_figi(next(&events).await), "A");
+
+        // сервер жив, пока отвечает на пинги: несколько периодов без разрыва
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert!(events.is_empty());
+
+        server.command(Command::Freeze).await;
+        assert!(matches!(next(&events).await, Event::Disconnected(0)));
+        assert!(matches!(next(&events).await, Event::Connected(0)));
+        assert!(matches!(next(&events).await, Event::Resubscribed(0, 1)));
+        assert_eq!(data_figi(next(&events).await), "A");
+        assert_eq!(next(&server.connections).await, 1);
+        assert_eq!(next(&server.connections).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let server = MockServer::start().await;
+        let (connection, events) = start(&server, PING_INTERVAL).await;
+        assert!(matches!(next(&events).await, Event::Connected(0)));
+
+        let failed = Request::CandleSubscribe { figi: UNKNOWN_FIGI.to_owned(), interval: Interval::MIN1 };
+        connection.send((RequestId::from_str("req-7").unwrap(), failed.clone())).await.unwrap();
+        match next(&events).await {
+            Event::Error { request: Some((id, req)), error } => {
+                assert_eq!((id.to_string(), req), ("req-7".to_owned(), failed));
+                assert!(error.contains(UNKNOWN_FIGI));
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+
+        // ошибка без известного запроса и мусор от сервера не рвут соединение
+        server.command(Command::Send(r#"{"event": "error", "time": "2019-08-07T15:35:00Z", "payload": {"error": "oops"}}"#.to_owned())).await;
+        assert!(matches!(next(&events).await, Event::Error { request: None, .. }));
+        server.command(Command::Send("not a json".to_owned())).await;
+        server.command(Command::Send(mock::orderbook("B", 1, 10.0).to_string())).await;
+        assert_eq!(data_figi(next(&events).await), "B");
+
+        // переподключение не повторяет отвергнутую подписку
+        server.command(Command::Disconnect).await;
+        assert!(matches!(next(&events).await, Event::Disconnected(0)));
+        assert!(matches!(next(&events).await, Event::Connected(0)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unavailable() {
+        // порт никто не слушает: о недоступности узнают и без прежнего соединения
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let (_connection, events) = start_at(uri, PING_INTERVAL);
+        assert!(matches!(next(&events).await, Event::Disconnected(0)));
+
+        // соединение принято, но рукопожатие не завершается: запросы все равно не копятся в канале
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (connection, _events) = start_at(format!("ws://{}", listener.local_addr().unwrap()), PING_INTERVAL);
+        for i in 0..200 {
+            let send = connection.send((RequestId::from_str(&format!("req-{}", i)).unwrap(), orderbook("A")));
+            tokio::time::timeout(Duration::from_secs(1), send).await.expect("request channel is full").unwrap();
         }
+        drop(listener);
     }
 }
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use super::entities::Request;
+
+/// Распределяет подписки по соединениям: брокер ограничивает число подписок на одно соединение
+#[derive(Debug)]
+pub struct Router {
+    limit: usize,
+    routes: HashMap<Request, usize>,
+    load: Vec<usize>,
+}
+
+impl Router {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, routes: HashMap::new(), load: Vec::new() }
+    }
+
+    /// Номер соединения для запроса. `None` - отписка от того, на что не подписаны
+    pub fn route(&mut self, req: &Request) -> Option<usize> {
+        let (key, subscribe) = req.subscription();
+        if !subscribe {
+            let shard = self.routes.remove(&key)?;
+            self.load[shard] -= 1;
+            self.shrink();
+            return Some(shard);
+        }
+        if let Some(&shard) = self.routes.get(&key) {
+            return Some(shard);
+        }
+        let shard = match self.load.iter().position(|&load|load < self.limit) {
+            Some(shard) => shard,
+            None => {
+                self.load.push(0);
+                self.load.len() - 1
+            }
+        };
+        self.load[shard] += 1;
+        self.routes.insert(key, shard);
+        Some(shard)
+    }
+
+    /// Брокер отверг подписку: ее место на соединении освобождается
+    pub fn release(&mut self, req: &Request) {
+        let (key, subscribe) = req.subscription();
+        if let Some(shard) = self.routes.remove(&key).filter(|_|subscribe) {
+            self.load[shard] -= 1;
+            self.shrink();
+        }
+    }
+
+    /// Сколько соединений нужно; первое остается всегда
+    pub fn shards(&self) -> usize {
+        self.load.len().max(1)
+    }
+
+    /// Пустые соединения в конце пула не нужны; пустые в середине займут следующие подписки
+    fn shrink(&mut self) {
+        while self.load.len() > 1 && self.load.last() == Some(&0) {
+            self.load.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn book(figi: &str) -> Request {
+        Request::OrderbookSubscribe { figi: figi.to_owned(), depth: 10 }
+    }
+
+    #[test]
+    fn test_route() {
+        let mut router = Router::new(2);
+        assert_eq!(router.route(&book("A")), Some(0));
+        assert_eq!(router.route(&book("B")), Some(0));
+        assert_eq!(router.route(&book("A")), Some(0));
+        assert_eq!(router.route(&book("C")), Some(1));
+        assert_eq!(router.load.len(), 2);
+
+        let unsubscribe = Request::OrderbookUnsubscribe { figi: "B".to_owned(), depth: 10 };
+        assert_eq!(router.route(&unsubscribe), Some(0));
+        assert_eq!(router.route(&unsubscribe), None);
+        assert_eq!(router.route(&Request::InfoSubscribe { figi: "D".to_owned() }), Some(0));
+        assert_eq!(router.load.len(), 2);
+
+        // отвергнутая подписка освобождает место, а опустевшее последнее соединение закрывается
+        router.release(&book("C"));
+        assert_eq!(router.shards(), 1);
+        router.release(&book("C"));
+        assert_eq!(router.load, vec![2]);
+        router.release(&book("A"));
+        assert_eq!(router.route(&book("C")), Some(0));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::entities::{Request, ResponseType};
+use crate::model::RequestId;
+
+/// Состояние подписки. Брокер ее не подтверждает, активной она становится с первым событием
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Pending,
+    Active,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct Subscription {
+    id: RequestId,
+    status: Status,
+}
+
+/// Сколько последних отписок помнить: брокер их не подтверждает
+const MAX_UNSUBSCRIBES: usize = 100;
+
+/// Подписки соединения с их идентификаторами и состоянием
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    state: HashMap<Request, Subscription>,
+    unsubscribes: VecDeque<RequestId>,
+}
+
+impl Subscriptions {
+    /// Учитывает запрос; подписка заводится заново, отписка удаляет подписку
+    pub fn on_command(&mut self, id: RequestId, req: &Request) {
+        match req.subscription() {
+            (req, true) => { self.state.insert(req, Subscription { id, status: Status::Pending }); }
+            (req, false) => {
+                self.state.remove(&req);
+                if self.unsubscribes.len() == MAX_UNSUBSCRIBES {
+                    self.unsubscribes.pop_front();
+                }
+                self.unsubscribes.push_back(id);
+            }
+        }
+    }
+
+    /// Ошибка на отписку, например от неудачной подписки, никому не интересна
+    pub fn is_unsubscribe(&self, request_id: &str) -> bool {
+        request_id.parse().is_ok_and(|id: RequestId|self.unsubscribes.contains(&id))
+    }
+
+    /// Событие с данными подтверждает подписку
+    pub fn on_data(&mut self, kind: &ResponseType) {
+        let sub = kind.subscription().and_then(|req|self.state.get_mut(&req).map(|s|(req, s)));
+        if let Some((req, sub)) = sub {
+            if sub.status != Status::Active {
+                log::info!("{} {:?} is active", sub.id, req);
+                sub.status = Status::Active;
+            }
+        }
+    }
+
+    /// Помечает подписку с этим идентификатором неудачной
+    pub fn on_error(&mut self, request_id: &str, error: &str) -> Option<(RequestId, Request)> {
+        let id: RequestId = request_id.parse().ok()?;
+        let (req, sub) = self.state.iter_mut().find(|(_, s)|s.id == id)?;
+        sub.status = Status::Failed(error.to_owned());
+        Some((id, req.clone()))
+    }
+
+    /// Подписки, которые нужно восстановить после переподключения: неудачные не повторяем
+    pub fn to_resubscribe(&self) -> impl Iterator<Item = (&Request, RequestId)> {
+        self.state.iter()
+            .filter(|(_, s)|!matches!(s.status, Status::Failed(_)))
+            .map(|(req, s)|(req, s.id))
+    }
+
+    /// После переподключения подписки снова ждут первого события
+    pub fn reset(&mut self) {
+        for sub in self.state.values_mut() {
+            if sub.status == Status::Active {
+                sub.status = Status::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status<'a>(subs: &'a Subscriptions, req: &Request) -> Option<&'a Status> {
+        subs.state.get(req).map(|s|&s.status)
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut subs = Subscriptions::default();
+        let book = Request::OrderbookSubscribe { figi: "BBG0013HGFT4".to_owned(), depth: 2 };
+        let info = Request::InfoSubscribe { figi: "UNKNOWN".to_owned() };
+        let (book_id, info_id) = (RequestId::new(), RequestId::new());
+        subs.on_command(book_id, &book);
+        subs.on_command(info_id, &info);
+        assert_eq!(status(&subs, &book), Some(&Status::Pending));
+
+        let data: crate::streaming::entities::Response = serde_json::from_str(r#"{
+            "event": "orderbook", "time": "2019-08-07T15:35:00.029721253Z",
+            "payload": {"figi": "BBG0013HGFT4", "depth": 2, "bids": [[64.35, 204]], "asks": [[64.38, 227]]}
+        }"#).unwrap();
+        subs.on_data(&data.kind);
+        assert_eq!(status(&subs, &book), Some(&Status::Active));
+
+        assert_eq!(subs.on_error(&info_id.to_string(), "unknown figi"), Some((info_id, info.clone())));
+        assert_eq!(subs.on_error("req-0", "unknown"), None);
+        assert_eq!(subs.to_resubscribe().map(|(r, _)|r.clone()).collect::<Vec<_>>(), vec![book.clone()]);
+
+        let unsubscribe = RequestId::new();
+        subs.on_command(unsubscribe, &Request::OrderbookUnsubscribe { figi: "BBG0013HGFT4".to_owned(), depth: 2 });
+        assert_eq!(status(&subs, &book), None);
+        assert!(subs.is_unsubscribe(&unsubscribe.to_string()));
+        assert!(!subs.is_unsubscribe(&info_id.to_string()));
+    }
+}
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use async_channel::Receiver;
 use log::info;
 
-use crate::{model::Stock, strategy::{ConfigError, Strategy, StrategyKind}}; 
+use crate::{instruments::InstrumentIndex, rest::SandboxRequest, model::{Account, AccountKind, Operation, OperationKind, OrderKind, Portfolio, Stock}, strategy::{ConfigError, Strategy, StrategyKind}}; 
 use telegram_bot::*;
 use super::fsm::State;
 use super::persistent::SavedState;
@@ -13,6 +13,8 @@ type Response = crate::trader::entities::Response<StrategyKind>;
 pub enum Event {
     Start,
     Portfolio,
+    History,
+    Sandbox(String),
     Strategies,
     Strategy,
     Finish,
@@ -33,6 +35,11 @@ impl From<UpdateKind> for Event {
                         match invoke(&entity, &data).as_ref() {
                             "/start" => return Self::Start,
                             "/portfolio" => return Self::Portfolio,
+                            "/history" => return Self::History,
+                            "/sandbox" => {
+                                let args: Vec<_> = data.encode_utf16().skip((entity.offset + entity.length) as usize).collect();
+                                return Self::Sandbox(String::from_utf16_lossy(&args).trim().to_owned())
+                            }
                             "/strategies" => return Self::Strategies,
                             "/strategy" => return Self::Strategy,
                             "/finish" => return Self::Finish,
@@ -59,14 +66,24 @@ pub enum ResponseMessage {
     RequestToken,
     RequestStrategyName,
     TraderStarted,
+    SelectAccount(Vec<Account>),
+    AccountSelected,
+    SandboxRegistered(String),
+    SandboxDone,
     InProgress,
     TraderStopped,
     SelectStrategy,
     SelectStrategyParam(Vec<(&'static str, &'static str)>),
     RequestParamValue,
     StrategyAdded,
+    StrategyConflict(Vec<String>),
+    StrategyDiscarded,
     Strategies,
     StrategyInfo(String, StrategyKind),
+    History(Vec<Operation>),
+    Portfolio(Portfolio),
+    ParamUpdated,
+    EditFinished,
     Err(String),
 }
 
@@ -84,6 +101,7 @@ impl Storage {
     pub fn as_saved_state(&self) -> Option<SavedState<StrategyKind>> {
         Some(SavedState::new( 
             self.state.token()?.to_owned(),
+            self.state.account().map(ToOwned::to_owned),
             self.context.strategies.clone()
         ))
     }
@@ -93,19 +111,23 @@ impl Storage {
     pub fn state(&self) -> &State {
         &self.state
     }
+    pub async fn on_trader(&mut self, response: &Response) {
+        let mut state = State::New;
+        std::mem::swap(&mut self.state, &mut state);
+        self.state = state.on_trader(&mut self.context, response).await;
+    }
     pub async fn on_event(&mut self, event: Event) -> Option<Receiver<Response>> {
         let mut state = State::New;
         std::mem::swap(&mut self.state, &mut state);
-        let is_waiting_token = matches!(&state, &State::WaitingToken);
+        let renews_handle = matches!(&state, &State::WaitingToken | &State::ChoosingAccount(_));
         let mut result = None;
         self.state = match state.on_event(&mut self.context, event).await {
-            Ok(State::Connected(handle)) => {
-                if is_waiting_token {
-                    result = Some(handle.receiver());
+            Ok(state) => {
+                if renews_handle {
+                    result = state.handle().map(|h|h.receiver());
                 }
-                State::Connected(handle)
+                state
             }
-            Ok(state) => state,
             Err(_e) => {
                 self.context.send(ResponseMessage::TraderStopped).await;
                 State::New
@@ -115,10 +137,20 @@ impl Storage {
     }
 }
 
+const SANDBOX_USAGE: &str = "Команды песочницы:
+/sandbox register - новый счет
+/sandbox currency USD 1000 - задать баланс валюты
+/sandbox position SBER 10 - задать количество бумаг
+/sandbox clear - сбросить все позиции
+/sandbox remove - удалить текущий счет";
+
+/// Сколько вариантов показывать, если бумага не найдена точно
+const SEARCH_LIMIT: usize = 5;
+
 pub struct Context {
     api: Api,
     chat_id: ChatId,
-    stocks: HashMap<String, Stock>,
+    stocks: InstrumentIndex,
     strategy_types: HashMap<String, StrategyKind>,
     strategies: HashMap<String, StrategyKind>,
 }
@@ -137,27 +169,112 @@ impl Context {
         buttons.into()
     }
     pub async fn set_parameter<S: Strategy>(&self, strategy: &mut S, key: &str, value: String) -> Result<(),ConfigError> {
+        let (key, value) = self.resolve_parameter(key, value).await?;
+        strategy.configure(&key, value)
+    }
+    /// Переводит параметр из чата в параметр стратегии: тикер превращается в figi
+    pub async fn resolve_parameter(&self, key: &str, value: String) -> Result<(String, String), ConfigError> {
         if key == "ticker" {
-            if let Some(value) = self.stocks.get(&value) {
-                strategy.configure("figi", value.figi.clone())?;
-                self.api.send(self.chat_id.text(format!("Бумага найдена: {}", value.name))).await;
+            let found = match self.stocks.get(&value) {
+                Some(stock) => Some(stock),
+                None => match self.stocks.search(&value, SEARCH_LIMIT).as_slice() {
+                    [stock] => Some(*stock),
+                    [] => None,
+                    candidates => {
+                        let text = candidates.iter().fold("Возможно, ты имел в виду:".to_owned(), |prev, s| {
+                            format!("{}\n\t{} ({}): {}", prev, s.ticker, s.isin.as_deref().unwrap_or("-"), s.name)
+                        });
+                        self.reply(self.chat_id.text(text)).await;
+                        None
+                    }
+                },
+            };
+            if let Some(value) = found {
+                self.reply(self.chat_id.text(format!("Бумага найдена: {} ({})", value.name, value.ticker))).await;
+                Ok(("figi".to_owned(), value.figi.clone()))
             } else {
-                return Err(ConfigError::TICKER_NOT_FOUND);
+                Err(ConfigError::TICKER_NOT_FOUND)
             }
         } else {
-            strategy.configure(key, value)?;
+            Ok((key.to_owned(), value))
         }
-        Ok(())
+    }
+    /// Разбирает аргументы команды /sandbox
+    pub fn sandbox_request(&self, args: &str) -> Result<SandboxRequest, String> {
+        let balance = |s: &str|s.parse::<f64>().map_err(|_|format!("Не понял количество: {}", s));
+        let args: Vec<_> = args.split_whitespace().collect();
+        Ok(match args.as_slice() {
+            ["register"] => SandboxRequest::Register,
+            ["clear"] => SandboxRequest::Clear,
+            ["remove"] => SandboxRequest::Remove,
+            ["currency", currency, value] => {
+                let currency = serde_json::from_value(serde_json::Value::String(currency.to_uppercase()))
+                    .map_err(|_|format!("Не знаю такую валюту: {}", currency))?;
+                SandboxRequest::SetCurrency { currency, balance: balance(value)? }
+            }
+            ["position", ticker, value] => {
+                let stock = self.stocks.get(ticker).ok_or_else(|| ConfigError::TICKER_NOT_FOUND.to_string())?;
+                SandboxRequest::SetPosition { figi: stock.figi.clone(), balance: balance(value)? }
+            }
+            _ => return Err(SANDBOX_USAGE.to_owned()),
+        })
+    }
+    pub fn stock_by_figi(&self, figi: &str) -> Option<&Stock> {
+        self.stocks.by_figi(figi)
+    }
+    fn operation_text(&self, op: &Operation) -> String {
+        let name = op.figi.as_ref()
+            .map(|figi|self.stock_by_figi(figi).map_or(figi.as_str(), |s|s.ticker.as_str()))
+            .unwrap_or("");
+        let what = match &op.kind {
+            OperationKind::Trade { kind, trades, .. } => {
+                let quantity: u32 = trades.iter().map(|t|t.quantity).sum();
+                let amount: f64 = trades.iter().map(|t|t.price * t.quantity as f64).sum();
+                let price = if quantity > 0 { amount / quantity as f64 } else { 0.0 };
+                let kind = if *kind == OrderKind::Buy { "Покупка" } else { "Продажа" };
+                format!("{} {} {} шт по {:.4}", kind, name, quantity, price)
+            }
+            OperationKind::Commission => format!("Комиссия {}", name),
+            OperationKind::Dividend => format!("Дивиденды {}", name),
+            OperationKind::Coupon => format!("Купон {}", name),
+            OperationKind::Repayment => format!("Погашение {}", name),
+            OperationKind::Tax => "Налог".to_owned(),
+            OperationKind::Cash => "Ввод/вывод денег".to_owned(),
+            OperationKind::Transfer => format!("Перевод бумаг {}", name),
+            OperationKind::Other => "Прочее".to_owned(),
+        };
+        format!("{} {}: {:.2} {:?}", op.time.format("%d.%m %H:%M"), what, op.payment, op.currency)
     }
     pub fn strategy(&self, key: &str) -> Option<&StrategyKind> {
         self.strategies.get(key)
     }
+    /// Ошибку отправки в чат только логируем: сообщить о ней все равно некуда
+    async fn reply<R: Request>(&self, request: R) {
+        if let Err(e) = self.api.send(request).await {
+            log::error!("telegram send to {} failed: {}", self.chat_id, e);
+        }
+    }
     pub async fn send(&self, msg: ResponseMessage) {
         let chat_id = self.chat_id;
         match msg {
             ResponseMessage::Dummy => { self.api.send(chat_id.text("Сорян, мне нечего ответить...")).await; }
             ResponseMessage::RequestToken => { self.api.send(chat_id.text("Принял, засылай токен")).await; }
             ResponseMessage::TraderStarted => { self.api.send(chat_id.text("Красава, подключаюсь...")).await; }
+            ResponseMessage::SelectAccount(accounts) => {
+                let buttons: Vec<_> = accounts.into_iter().map(|Account { id, kind }| {
+                    let name = match kind {
+                        AccountKind::Tinkoff => "Брокерский счет",
+                        AccountKind::TinkoffIis => "ИИС",
+                    };
+                    vec![InlineKeyboardButton::callback(format!("{} {}", name, id), id)]
+                }).collect();
+                let mut msg = chat_id.text("На каком счете торгуем?");
+                msg.reply_markup(buttons);
+                self.reply(msg).await;
+            }
+            ResponseMessage::AccountSelected => self.reply(chat_id.text("Ок, переключаюсь на этот счет")).await,
+            ResponseMessage::SandboxRegistered(id) => self.reply(chat_id.text(format!("Зарегистрирован счет в песочнице: {}", id))).await,
+            ResponseMessage::SandboxDone => self.reply(chat_id.text("Готово")).await,
             ResponseMessage::InProgress => { self.api.send(SendChatAction::new(chat_id, ChatAction::Typing)).await; }
             ResponseMessage::TraderStopped => { self.api.send(chat_id.text("Упс, я обосрался... Давай сначала")).await; }
             ResponseMessage::RequestStrategyName => { self.api.send(chat_id.text("Придумай имя для своей стратегии")).await; }
@@ -180,6 +297,49 @@ impl Context {
             }
             ResponseMessage::RequestParamValue => { self.api.send(chat_id.text("Ок, пиши значение")).await; }
             ResponseMessage::StrategyAdded => { self.api.send(chat_id.text("Ок, стратегия добавлена")).await; }
+            ResponseMessage::StrategyConflict(conflicts) => {
+                let text = format!("Этой же бумагой уже торгуют стратегии: {}\nВсе равно добавить?", conflicts.join(", "));
+                let mut msg = chat_id.text(text);
+                msg.reply_markup(vec![vec![
+                    InlineKeyboardButton::callback("Да", "yes"),
+                    InlineKeyboardButton::callback("Нет", "no"),
+                ]]);
+                self.reply(msg).await;
+            }
+            ResponseMessage::History(mut operations) => {
+                operations.sort_by_key(|op|op.time);
+                let last = operations.iter().rev().take(20).rev().map(|op|self.operation_text(op));
+                let mut text = last.fold("История операций:".to_owned(), |prev, line| format!("{}\n{}", prev, line));
+                for ((kind, currency), total) in history_totals(&operations) {
+                    text = format!("{}\n{}: {:.2} {}", text, kind, total, currency);
+                }
+                self.reply(chat_id.text(text)).await;
+            }
+            ResponseMessage::Portfolio(mut portfolio) => {
+                portfolio.positions.sort_by(|a, b|a.0.ticker.cmp(&b.0.ticker));
+                let mut text = portfolio.positions.iter().fold("Твой портфель:".to_owned(), |prev, (stock, position)| {
+                    let currency = position.currency.map(|c|format!("{:?}", c)).unwrap_or_default();
+                    match position.value() {
+                        Some(value) => format!("{}\n\t{}: {} шт, {:.2} {} ({:+.2})", prev, stock.name, position.balance, value, currency, position.expected_yield),
+                        None => format!("{}\n\t{}: {} шт", prev, stock.name, position.balance),
+                    }
+                });
+                if !portfolio.currencies.is_empty() {
+                    text = portfolio.currencies.iter().fold(format!("{}\nВалюта:", text), |prev, (currency, balance)| {
+                        format!("{}\n\t{:?}: {:.2}", prev, currency, balance)
+                    });
+                }
+                let totals: Vec<_> = portfolio.totals.iter().map(|(c, v)|format!("{:.2} {:?}", v, c)).collect();
+                text = format!("{}\nИтого: {}", text, totals.join(" / "));
+                if !portfolio.missing_rates.is_empty() {
+                    let missing: Vec<_> = portfolio.missing_rates.iter().map(|c|format!("{:?}", c)).collect();
+                    text = format!("{}\nНет курса для {}, итог неполный", text, missing.join(", "));
+                }
+                self.reply(chat_id.text(text)).await;
+            }
+            ResponseMessage::ParamUpdated => self.reply(chat_id.text("Ок, параметр изменен. Можно менять дальше или /finish")).await,
+            ResponseMessage::EditFinished => self.reply(chat_id.text("Ок, закончили")).await,
+            ResponseMessage::StrategyDiscarded => self.reply(chat_id.text("Ок, не добавляю")).await,
             ResponseMessage::Strategies => {
                 let mut msg = chat_id.text("Стратегии".to_owned());
                 let buttons: Vec<_> = self.strategies.keys().map(|k|vec![InlineKeyboardButton::callback(k.clone(), k.clone())]).collect();
@@ -189,17 +349,77 @@ impl Context {
             ResponseMessage::Err(s) => { self.api.send(chat_id.text(s)).await; }
             ResponseMessage::StrategyInfo(key, s) => {
                 let msg = format!("Инфо по стратегии {}\n{}, \n\t{}\nБаланс: {}",key, s.name(), s.description(), s.balance());
-                self.api.send(chat_id.text(msg)).await;
+                let mut msg = chat_id.text(msg);
+                msg.reply_markup(vec![vec![InlineKeyboardButton::callback("Изменить параметры", "edit")]]);
+                self.reply(msg).await;
             }
         }
     }
     pub fn update_strategies(&mut self, strategies: HashMap<String, StrategyKind>) {
         self.strategies = strategies;
     }
+    pub fn set_strategy(&mut self, key: String, strategy: StrategyKind) {
+        self.strategies.insert(key, strategy);
+    }
     pub fn strategy_by_type(&self, type_name: &str) -> Option<StrategyKind> {
         self.strategy_types.get(type_name).map(Clone::clone)
     }
-    pub fn set_stocks(&mut self, stocks: HashMap<String, Stock>) {
-        self.stocks = stocks;
+    pub fn set_stocks(&mut self, stocks: Vec<Stock>) {
+        self.stocks = InstrumentIndex::new(stocks);
+    }
+}
+
+/// Итоги по комиссиям, доходам и налогам в каждой валюте, по порядку названий
+fn history_totals(operations: &[Operation]) -> Vec<((&'static str, String), f64)> {
+    let mut totals: HashMap<(&str, String), f64> = HashMap::new();
+    for op in operations {
+        let kind = match op.kind {
+            OperationKind::Trade { commission, .. } => {
+                *totals.entry(("Комиссии", format!("{:?}", op.currency))).or_default() += commission;
+                continue
+            }
+            OperationKind::Commission => "Комиссии",
+            OperationKind::Dividend => "Дивиденды",
+            OperationKind::Coupon => "Купоны",
+            OperationKind::Tax => "Налоги",
+            _ => continue,
+        };
+        *totals.entry((kind, format!("{:?}", op.currency))).or_default() += op.payment;
+    }
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_by(|a, b|a.0.cmp(&b.0));
+    totals
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Currency;
+
+    fn operation(kind: OperationKind, currency: Currency, payment: f64) -> Operation {
+        let time = chrono::DateTime::parse_from_rfc3339("2021-02-01T10:00:00+03:00").unwrap();
+        Operation { time, figi: None, currency, payment, kind }
+    }
+
+    #[test]
+    fn test_history_totals() {
+        let trade = |commission| OperationKind::Trade { kind: OrderKind::Buy, trades: Vec::new(), commission };
+        let operations = vec![
+            operation(trade(-1.0), Currency::USD, -100.0),
+            operation(trade(-2.0), Currency::RUB, -2000.0),
+            operation(OperationKind::Commission, Currency::USD, -0.5),
+            operation(OperationKind::Dividend, Currency::USD, 10.0),
+            operation(OperationKind::Tax, Currency::USD, -1.3),
+            operation(OperationKind::Cash, Currency::RUB, 5000.0),
+        ];
+        let totals: Vec<_> = history_totals(&operations).into_iter()
+            .map(|((kind, currency), total)|(kind, currency, total))
+            .collect();
+        assert_eq!(totals, vec![
+            ("Дивиденды", "USD".to_owned(), 10.0),
+            ("Комиссии", "RUB".to_owned(), -2.0),
+            ("Комиссии", "USD".to_owned(), -1.5),
+            ("Налоги", "USD".to_owned(), -1.3),
+        ]);
     }
 }
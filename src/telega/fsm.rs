@@ -1,24 +1,32 @@
 use crate::model::{ChannelStopped, ServiceHandle};
+use crate::rest::SandboxRequest;
 use crate::strategy::{Strategy as _, StrategyKind};
-use crate::trader::entities::{Request, Response};
+use crate::trader::entities::{Key, Request, Response};
 use crate::strategy::StrategyKind as Strategy;
 
 use super::entities::*;
 #[derive(Debug)]
 pub struct TraderHandle {
     token: String,
+    account: Option<String>,
     handle: ServiceHandle<Request<StrategyKind>, Response<StrategyKind>>,
 }
 
 impl TraderHandle {
-    pub fn create(token: String) -> Self {
+    pub fn create(token: String, account: Option<String>) -> Self {
         use crate::trader::{Trader, TraderConf};
+        use crate::streaming::{StreamingHub, spawn_streaming_stats};
+        let hub = StreamingHub::default();
+        spawn_streaming_stats(&hub);
         let conf = TraderConf {
             rest_uri: "https://api-invest.tinkoff.ru/openapi/sandbox/".to_owned(),
             streaming_uri: "wss://api-invest.tinkoff.ru/openapi/md/v1/md-openapi/ws".to_owned(),
             token: token.clone(),
+            account: account.clone(),
+            max_data_age: crate::model::DEFAULT_MAX_AGE,
+            hub,
         };
-        Self {token, handle: Trader::start(conf)}
+        Self {token, account, handle: Trader::start(conf)}
     }
 }
 
@@ -45,49 +53,101 @@ pub struct StrategyParam {
     name: String,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditedParam {
+    key: Key,
+    name: String,
+}
+
 #[derive(Debug)]
 pub enum State {
     New, 
     WaitingToken,
+    ChoosingAccount(Handle),
     Connected(Handle),
     ChoosingStrategyKind(Handle),
     WaitingStrategyName(Handle, Strategy),
     ChoosingStrategyParam(Handle, NamedStrategy),
     WaitingStrategyParam(Handle, StrategyParam),
     ChoosingStrategy(Handle),
+    AddingStrategy(Handle),
+    ConfirmingStrategy(Handle, NamedStrategy),
+    ViewingStrategy(Handle, Key),
+    EditingStrategy(Handle, Key),
+    WaitingEditedParam(Handle, EditedParam),
 }
 
 impl State {
     pub fn create(handle: Handle) -> Self {
         Self::Connected(handle)
     }
-    pub fn token(&self) -> Option<&str> {
+    pub fn handle(&self) -> Option<&Handle> {
         match self {
             State::New => None,
             State::WaitingToken => None,
-            State::Connected(h) => Some(h.token.as_ref()),
-            State::ChoosingStrategyKind(h) => Some(h.token.as_ref()),
-            State::WaitingStrategyName(h, ..) => Some(h.token.as_ref()),
-            State::ChoosingStrategyParam(h, ..) => Some(h.token.as_ref()),
-            State::WaitingStrategyParam(h, ..) => Some(h.token.as_ref()),
-            State::ChoosingStrategy(h) => Some(h.token.as_ref()),
+            State::ChoosingAccount(h) => Some(h),
+            State::Connected(h) => Some(h),
+            State::ChoosingStrategyKind(h) => Some(h),
+            State::WaitingStrategyName(h, ..) => Some(h),
+            State::ChoosingStrategyParam(h, ..) => Some(h),
+            State::WaitingStrategyParam(h, ..) => Some(h),
+            State::ChoosingStrategy(h) => Some(h),
+            State::AddingStrategy(h) => Some(h),
+            State::ConfirmingStrategy(h, ..) => Some(h),
+            State::ViewingStrategy(h, ..) => Some(h),
+            State::EditingStrategy(h, ..) => Some(h),
+            State::WaitingEditedParam(h, ..) => Some(h),
         }
     }
+    pub fn token(&self) -> Option<&str> {
+        self.handle().map(|h|h.token.as_ref())
+    }
+    pub fn account(&self) -> Option<&str> {
+        self.handle().and_then(|h|h.account.as_deref())
+    }
     pub async fn on_event(self, ctx: &mut Context, event: Event) -> Result<State, ChannelStopped> {
         use State as S;
         use Event as E;
         use ResponseMessage as RM;
-        Ok( match (self, event) {
+        let state = match (self, &event) {
+            (S::ViewingStrategy(handle, _), e) if !matches!(e, E::Select(_)) => S::Connected(handle),
+            (S::ChoosingAccount(handle), e) if !matches!(e, E::Select(_)) => S::Connected(handle),
+            (state, _) => state,
+        };
+        Ok( match (state, event) {
             (_, E::Start) => {
                 ctx.send(RM::RequestToken).await;
                 S::WaitingToken
             },
-            (S::WaitingToken, E::Text(token)) => connect(ctx, token).await,
+            (S::WaitingToken, E::Text(token)) => connect(ctx, token).await?,
+            (S::ChoosingAccount(mut handle), E::Select(account)) => {
+                handle.send(Request::SelectAccount(Some(account.clone()))).await?;
+                handle.account = Some(account);
+                ctx.send(RM::AccountSelected).await;
+                S::Connected(handle)
+            }
             (S::Connected(handle), E::Portfolio) => {
                 handle.send(Request::Portfolio).await?;
                 ctx.send(RM::InProgress).await;
                 S::Connected(handle)
             }
+            (S::Connected(handle), E::History) => {
+                let to = chrono::Utc::now().with_timezone(&chrono::FixedOffset::east_opt(3*3600).expect("valid offset"));
+                let from = to - chrono::Duration::days(30);
+                handle.send(Request::Operations { from, to, figi: None }).await?;
+                ctx.send(RM::InProgress).await;
+                S::Connected(handle)
+            }
+            (S::Connected(handle), E::Sandbox(args)) => {
+                match ctx.sandbox_request(&args) {
+                    Ok(request) => {
+                        handle.send(Request::Sandbox(request)).await?;
+                        ctx.send(RM::InProgress).await;
+                    }
+                    Err(e) => ctx.send(RM::Err(e)).await,
+                }
+                S::Connected(handle)
+            }
             (S::Connected(handle), E::Strategies) => {
                 ctx.send(RM::Strategies).await;
                 S::ChoosingStrategy(handle)
@@ -110,8 +170,18 @@ impl State {
             (S::ChoosingStrategyParam(handle, NamedStrategy { strategy, name }), E::Finish) => {
                 //ctx.add_strategy(name.clone(), strategy.clone());
                 handle.send(Request::AddStrategy(name, strategy)).await?;
-                ctx.send(RM::StrategyAdded).await;
-                S::Connected(handle)
+                ctx.send(RM::InProgress).await;
+                S::AddingStrategy(handle)
+            }
+            (S::ConfirmingStrategy(handle, NamedStrategy { strategy, name }), E::Select(answer)) => {
+                if answer == "yes" {
+                    handle.send(Request::ForceAddStrategy(name, strategy)).await?;
+                    ctx.send(RM::InProgress).await;
+                    S::AddingStrategy(handle)
+                } else {
+                    ctx.send(RM::StrategyDiscarded).await;
+                    S::Connected(handle)
+                }
             }
             (S::ChoosingStrategyParam(handle, strategy), E::Select(name)) => {
                 ctx.send(RM::RequestParamValue).await;
@@ -125,19 +195,93 @@ impl State {
             }
             (S::ChoosingStrategy(handle), E::Select(key)) =>  {
                 if let Some(strategy) = ctx.strategy(key.as_ref()) {
-                    ctx.send(RM::StrategyInfo(key, strategy.clone())).await;
-                    S::Connected(handle)
+                    ctx.send(RM::StrategyInfo(key.clone(), strategy.clone())).await;
+                    S::ViewingStrategy(handle, key)
                 } else {
                     ctx.send(RM::Dummy).await;
                     S::ChoosingStrategy(handle)
                 }
             }
+            (S::ViewingStrategy(handle, key), E::Select(action)) if action == "edit" => {
+                to_editing_strategy(ctx, handle, key).await
+            }
+            (S::EditingStrategy(handle, _), E::Finish) => {
+                ctx.send(RM::EditFinished).await;
+                S::Connected(handle)
+            }
+            (S::EditingStrategy(handle, key), E::Select(name)) => {
+                ctx.send(RM::RequestParamValue).await;
+                S::WaitingEditedParam(handle, EditedParam { key, name })
+            }
+            (S::WaitingEditedParam(handle, EditedParam { key, name }), E::Text(value)) => {
+                let mut strategy = match ctx.strategy(&key) {
+                    Some(s) => s.clone(),
+                    None => {
+                        ctx.send(RM::Dummy).await;
+                        return Ok(S::Connected(handle));
+                    }
+                };
+                let checked = match ctx.resolve_parameter(&name, value).await {
+                    Ok((param, value)) => strategy.configure(&param, value.clone()).map(|_|(param, value)),
+                    Err(e) => Err(e),
+                };
+                match checked {
+                    Ok((param, value)) => {
+                        handle.send(Request::ConfigureStrategy(key.clone(), param, value)).await?;
+                        ctx.set_strategy(key.clone(), strategy);
+                        ctx.send(RM::ParamUpdated).await;
+                        to_editing_strategy(ctx, handle, key).await
+                    }
+                    Err(e) => with_err(ctx, S::WaitingEditedParam(handle, EditedParam { key, name }), e).await
+                }
+            }
             (state, _) => {
                 ctx.send(RM::Dummy).await;
                 state
             },
         })
     }
+    pub async fn on_trader(self, ctx: &mut Context, response: &Response<StrategyKind>) -> State {
+        use State as S;
+        use ResponseMessage as RM;
+        match (self, response) {
+            (S::ChoosingAccount(handle), Response::Accounts(accounts)) if accounts.len() > 1 => {
+                ctx.send(RM::SelectAccount(accounts.clone())).await;
+                S::ChoosingAccount(handle)
+            }
+            (S::ChoosingAccount(handle), Response::Accounts(_)) => S::Connected(handle),
+            (S::AddingStrategy(handle), Response::Strategies(_)) => {
+                ctx.send(RM::StrategyAdded).await;
+                S::Connected(handle)
+            }
+            (S::AddingStrategy(handle), Response::Conflict(name, strategy, conflicts)) => {
+                ctx.send(RM::StrategyConflict(conflicts.clone())).await;
+                let strategy = NamedStrategy { strategy: strategy.clone(), name: name.clone() };
+                S::ConfirmingStrategy(handle, strategy)
+            }
+            (S::Connected(handle), Response::SandboxRegistered(account)) => {
+                ctx.send(RM::SandboxRegistered(account.id.clone())).await;
+                choose_account(handle).await
+            }
+            (S::Connected(handle), Response::SandboxDone(request)) => {
+                ctx.send(RM::SandboxDone).await;
+                // текущий счет удален: предлагаем выбрать другой
+                if let SandboxRequest::Remove = request {
+                    choose_account(handle).await
+                } else {
+                    S::Connected(handle)
+                }
+            }
+            (state, _) => state,
+        }
+    }
+}
+
+async fn choose_account(handle: Handle) -> State {
+    match handle.send(Request::Accounts).await {
+        Ok(_) => State::ChoosingAccount(handle),
+        Err(_) => State::New,
+    }
 }
 
 async fn with_err<E: std::fmt::Display>(ctx: &mut Context, state: State, err: E) -> State {
@@ -152,8 +296,23 @@ async fn to_choosing_strategy_param(ctx: &mut Context, handle: Handle, strategy:
     State::ChoosingStrategyParam(handle, strategy)
 }
 
-async fn connect(ctx: &mut Context, token: String) -> State {
-    let result = State::create(Handle::create(token));
+async fn to_editing_strategy(ctx: &mut Context, handle: Handle, key: Key) -> State {
+    match ctx.strategy(&key) {
+        Some(strategy) => {
+            let params = strategy.params();
+            ctx.send(ResponseMessage::SelectStrategyParam(params)).await;
+            State::EditingStrategy(handle, key)
+        }
+        None => {
+            ctx.send(ResponseMessage::Dummy).await;
+            State::Connected(handle)
+        }
+    }
+}
+
+async fn connect(ctx: &mut Context, token: String) -> Result<State, ChannelStopped> {
+    let handle = Handle::create(token, None);
+    handle.send(Request::Accounts).await?;
     ctx.send(ResponseMessage::TraderStarted).await;
-    result
+    Ok(State::ChoosingAccount(handle))
 }
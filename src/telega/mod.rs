@@ -32,28 +32,38 @@ impl Bot {
     async fn on_trader(&mut self, chat: ChatId, response: Response) -> Result<(), Error> {
         let storage = self.storage.get_mut(&chat).expect("storage must be");
         match response {
-            Response::Portfolio(positions) => {
-                let text = positions.into_iter().fold("Твой портфель:".to_owned(), |prev, (stock, position)| {
-                    format!("{}\n\t{}: {}\n", prev, stock.name, position.balance)
-                });
-                self.api.send(chat.text(text)).await?;
-            }
-            Response::Stocks(v) => {
-                let stocks = v.into_iter().fold(HashMap::new(), |mut map, stock| {
-                    map.insert(stock.ticker.clone(), stock);
-                    map
-                });
-                storage.context.set_stocks(stocks)
+            Response::Portfolio(portfolio) => {
+                storage.context.send(ResponseMessage::Portfolio(portfolio)).await;
             }
+            Response::Stocks(v) => storage.context.set_stocks(v),
             Response::Strategies(s) => {
-                storage.context.update_strategies(s);
+                storage.context.update_strategies(s.clone());
                 if let Some(saved) = storage.as_saved_state() {
                     self.cache.send(persistent::Request::Update(chat, saved)).await;
                     log::info!("strategies updated");
                 } else {
                     log::error!("invalid state: {:?}", storage.state())
                 }
+                storage.on_trader(&Response::Strategies(s)).await;
             },
+            response @ Response::Conflict(..) | response @ Response::Accounts(..) |
+            response @ Response::SandboxDone(..) | response @ Response::SandboxRegistered(..) => storage.on_trader(&response).await,
+            Response::Operations(operations) => storage.context.send(ResponseMessage::History(operations)).await,
+            Response::RestError(e) => {
+                self.api.send(chat.text(format!("Ошибка от брокера: {}", e))).await?;
+            }
+            Response::StreamingError(e) => {
+                self.api.send(chat.text(e)).await?;
+            }
+            Response::StreamingLost => {
+                self.api.send(chat.text("Пропало соединение с биржевыми данными, стратегии на паузе. Переподключаюсь...")).await?;
+            }
+            Response::StreamingRestored => {
+                self.api.send(chat.text("Соединение с биржевыми данными восстановлено")).await?;
+            }
+            Response::StrategyError(key, e) => {
+                self.api.send(chat.text(format!("Упс... {}: {}", key, e))).await?;
+            }
         }
         Ok(())
     }
@@ -88,10 +98,13 @@ impl Bot {
 
             for (chat, saved) in saved {
                 let mut storage = Storage::new(self.api.clone(), chat);
-                let handle = fsm::TraderHandle::create(saved.token());
+                let handle = fsm::TraderHandle::create(saved.token(), saved.account());
                 for (key, strategy) in saved.strategies() {
-                    use crate::trader::entities::Request::AddStrategy;
-                    handle.send(AddStrategy(key.clone(), strategy.clone())).await;
+                    use crate::trader::entities::Request::ForceAddStrategy;
+                    if handle.send(ForceAddStrategy(key.clone(), strategy.clone())).await.is_err() {
+                        log::error!("trader for {} stopped while restoring strategies", chat);
+                        break;
+                    }
                 }
                 self.traders.insert(chat, handle.receiver());
                 storage.set_state(fsm::State::create(handle));
@@ -10,16 +10,21 @@ use super::entities::{Context, Storage};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SavedState<S> {
     token: String,
+    #[serde(default)]
+    account: Option<String>,
     strategies: HashMap<t::Key, S>,
 }
 
 impl <S: Strategy + Send + Clone + 'static> SavedState<S> {
-    pub fn new(token: String, strategies: HashMap<t::Key, S>) -> Self {
-        Self { token, strategies}
+    pub fn new(token: String, account: Option<String>, strategies: HashMap<t::Key, S>) -> Self {
+        Self { token, account, strategies}
     }
     pub fn token(&self) -> String {
         self.token.clone()
     }
+    pub fn account(&self) -> Option<String> {
+        self.account.clone()
+    }
     pub fn strategies(&self) -> &HashMap<t::Key, S> {
         &self.strategies
     }
@@ -86,7 +91,7 @@ mod test {
     fn make_state() -> SavedState<StrategyKind> {
         let mut strategies = HashMap::new();
         strategies.insert("test1".to_owned(), StrategyKind::FixedAmount(Default::default()));
-        SavedState::new("token".to_owned(), strategies)
+        SavedState::new("token".to_owned(), None, strategies)
     }
 
     #[test]
@@ -0,0 +1,91 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::streaming::StreamingRequest;
+use super::entities::Key;
+
+/// Кому нужна подписка
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Consumer {
+    /// Позиции портфеля
+    Positions,
+    /// Курсы валют для оценки портфеля
+    Valuation,
+    Strategy(Key),
+}
+
+/// Что изменить в подписках стриминга
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub subscribe: Vec<StreamingRequest>,
+    pub unsubscribe: Vec<StreamingRequest>,
+}
+
+/// Подписки вместе с их потребителями: подписываемся с первым потребителем, отписываемся с уходом последнего
+#[derive(Debug, Default)]
+pub struct Demand {
+    consumers: HashMap<StreamingRequest, HashSet<Consumer>>,
+}
+
+impl Demand {
+    /// Заменяет все подписки потребителя новыми
+    pub fn set(&mut self, consumer: Consumer, requests: impl IntoIterator<Item = StreamingRequest>) -> Changes {
+        let requests: HashSet<_> = requests.into_iter().collect();
+        let mut changes = Changes::default();
+        self.consumers.retain(|req, consumers| {
+            if !requests.contains(req) && consumers.remove(&consumer) && consumers.is_empty() {
+                changes.unsubscribe.push(req.unsubscribe());
+                return false;
+            }
+            true
+        });
+        for req in requests {
+            let consumers = self.consumers.entry(req.clone()).or_default();
+            if consumers.is_empty() {
+                changes.subscribe.push(req);
+            }
+            consumers.insert(consumer.clone());
+        }
+        changes
+    }
+
+    pub fn consumers(&self, req: &StreamingRequest) -> impl Iterator<Item = &Consumer> {
+        self.consumers.get(req).into_iter().flatten()
+    }
+
+    /// Инструменты, на стаканы которых есть подписка
+    pub fn orderbooks(&self) -> impl Iterator<Item = &str> {
+        self.consumers.keys().filter_map(|req| match req {
+            StreamingRequest::OrderbookSubscribe { figi, .. } => Some(figi.as_str()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn book(figi: &str) -> StreamingRequest {
+        StreamingRequest::OrderbookSubscribe { figi: figi.to_owned(), depth: 10 }
+    }
+
+    #[test]
+    fn test_demand() {
+        let mut demand = Demand::default();
+        let changes = demand.set(Consumer::Positions, vec![book("A"), book("B")]);
+        assert_eq!(changes.subscribe.len(), 2);
+
+        let strategy = Consumer::Strategy("s".to_owned());
+        let changes = demand.set(strategy.clone(), vec![book("A")]);
+        assert!(changes.subscribe.is_empty() && changes.unsubscribe.is_empty());
+
+        // позиция по A закрыта, но стакан еще нужен стратегии
+        let changes = demand.set(Consumer::Positions, vec![book("B")]);
+        assert!(changes.subscribe.is_empty() && changes.unsubscribe.is_empty());
+        assert_eq!(demand.consumers(&book("A")).collect::<Vec<_>>(), vec![&strategy]);
+
+        let changes = demand.set(strategy, vec![]);
+        assert_eq!(changes.unsubscribe, vec![StreamingRequest::OrderbookUnsubscribe { figi: "A".to_owned(), depth: 10 }]);
+        assert_eq!(demand.orderbooks().collect::<Vec<_>>(), vec!["B"]);
+    }
+}
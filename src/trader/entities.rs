@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
-use crate::model::{Position, Stock};
+use crate::model::{Account, DateTime, Operation, Portfolio, Stock};
+use crate::rest::{RestError, SandboxRequest};
 
 pub type Key = String;
 
@@ -8,13 +9,29 @@ pub type Key = String;
 pub enum Request<S> {
     Portfolio,
     AddStrategy(Key, S),
+    ForceAddStrategy(Key, S),
     RemoveStrategy(Key),
+    ConfigureStrategy(Key, String, String),
     Strategies,
+    Operations { from: DateTime, to: DateTime, figi: Option<String> },
+    Accounts,
+    SelectAccount(Option<String>),
+    Sandbox(SandboxRequest),
 }
 
 #[derive(Debug, Clone)]
 pub enum Response<S> {
-    Portfolio(Vec<(Stock, Position)>),
+    Portfolio(Portfolio),
     Stocks(Vec<Stock>),
     Strategies(HashMap<Key, S>),
+    Conflict(Key, S, Vec<Key>),
+    StrategyError(Key, String),
+    RestError(RestError),
+    StreamingError(String),
+    StreamingLost,
+    StreamingRestored,
+    Operations(Vec<Operation>),
+    Accounts(Vec<Account>),
+    SandboxDone(SandboxRequest),
+    SandboxRegistered(Account),
 }
@@ -1,40 +1,200 @@
 pub mod entities;
+mod demand;
 
-use std::{collections::HashMap, time::SystemTime};
+use std::{collections::{HashMap, HashSet}, fmt::Display, time::{Duration, SystemTime}};
+use tokio::time::Instant;
 use async_channel::{Receiver, Sender};
 use entities::*;
 use crate::rest::*;
 use crate::streaming::*;
 use crate::model::*;
+use crate::instruments::{self, InstrumentIndex};
 use crate::strategy::{Strategy, Decision};
+use demand::{Consumer, Demand};
+
+enum OrderEvent {
+    Accepted(OrderState),
+    PartiallyFilled(OrderState, Fill),
+    Filled(OrderState, Fill),
+    Cancelled(OrderState),
+    Rejected(Order, String),
+}
+
+/// Стратегия принимает решения, только если все ее инструменты торгуются в разрешенном ей режиме
+fn is_trading<S: Strategy>(market: &Market, strategy: &S) -> bool {
+    let statuses = strategy.trade_statuses();
+    strategy.figis().iter().all(|figi| {
+        market.trade_status(figi).map(|status|statuses.contains(&status)).unwrap_or(false)
+    })
+}
+
+/// Кто и зачем отправил запрос
+#[derive(Debug)]
+enum Origin {
+    Trader,
+    Strategy(Key, Decision),
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Trader => f.write_str("trader"),
+            Origin::Strategy(key, decision) => write!(f, "strategy {} on {:?}", key, decision),
+        }
+    }
+}
+
+/// Стратегия, выставившая заявку, и когда брокер заявку принял
+struct Owner {
+    strategy: Key,
+    accepted: SystemTime,
+}
+
+/// Без шага цены и лота инструмента заявку не выставить, поэтому стратегия ждет справочник
+fn has_instruments<S: Strategy>(market: &Market, strategy: &S) -> bool {
+    strategy.figis().iter().all(|figi|market.stock(figi).is_some())
+}
+
+/// Стратегия принимает решения только по актуальным данным: замерший стакан выглядит как спокойный рынок
+fn has_fresh_data<S: Strategy>(market: &Market, strategy: &S) -> bool {
+    strategy.figis().iter().all(|figi|!market.is_stale(figi))
+}
+
+fn signed_lots(kind: OrderKind, quantity: u32) -> i32 {
+    match kind {
+        OrderKind::Buy => quantity as i32,
+        OrderKind::Sell => -(quantity as i32),
+    }
+}
+
+/// Находит исполнения и снятия по снимку портфеля. `true` у события - заявка завершена
+fn reconcile_orders(market: &Market, owners: &HashMap<String, Owner>, time: SystemTime, positions: &[(String, Position)], orders: &[OrderState]) -> Vec<(bool, OrderEvent)> {
+    let active: HashMap<&str, &OrderState> = orders.iter().map(|o|(o.order_id.as_str(), o)).collect();
+    let lots: HashMap<&str, i32> = positions.iter().map(|(figi, p)|(figi.as_str(), p.lots)).collect();
+    let known: Vec<OrderState> = market.inwork_orders()
+        .filter(|o|matches!(owners.get(&o.order_id), Some(owner) if owner.accepted < time))
+        .cloned()
+        .collect();
+    let mut unexplained: HashMap<String, i32> = HashMap::new();
+    for state in &known {
+        let figi = &state.order.figi;
+        let before = market.state(figi).map(|s|s.position.lots).unwrap_or(0);
+        let after = lots.get(figi.as_str()).copied().unwrap_or(0);
+        unexplained.insert(figi.clone(), after - before);
+    }
+    let mut events = Vec::new();
+    let mut finished = Vec::new();
+    for state in known {
+        match active.get(state.order_id.as_str()) {
+            Some(&actual) if actual.executed > state.executed => {
+                let quantity = actual.executed - state.executed;
+                *unexplained.get_mut(&state.order.figi).unwrap() -= signed_lots(state.order.kind, quantity);
+                let fill = Fill { limit_price: actual.order.price, quantity };
+                events.push((false, OrderEvent::PartiallyFilled(actual.clone(), fill)));
+            }
+            Some(_) => {}
+            None => finished.push(state),
+        }
+    }
+    for mut state in finished {
+        let delta = unexplained.get_mut(&state.order.figi).unwrap();
+        let available = signed_lots(state.order.kind, 1) * *delta;
+        let remaining = state.order.quantity - state.executed;
+        let quantity = remaining.min(available.max(0) as u32);
+        *delta -= signed_lots(state.order.kind, quantity);
+        state.executed += quantity;
+        let fill = Fill { limit_price: state.order.price, quantity };
+        if quantity == remaining {
+            state.status = OrderStatus::Fill;
+            events.push((true, OrderEvent::Filled(state, fill)));
+        } else {
+            if quantity > 0 {
+                events.push((false, OrderEvent::PartiallyFilled(state.clone(), fill)));
+            }
+            state.status = OrderStatus::Cancelled;
+            events.push((true, OrderEvent::Cancelled(state)));
+        }
+    }
+    events
+}
+
+/// Неизвестная трейдеру заявка брокера с теми же параметрами, что у заявки без ответа
+fn find_placed<'a>(owners: &HashMap<String, Owner>, orders: &'a [OrderState], order: &Order) -> Option<&'a OrderState> {
+    orders.iter()
+        .filter(|o|!owners.contains_key(&o.order_id))
+        .find(|o|o.order.figi == order.figi && o.order.kind == order.kind && o.order.quantity == order.quantity
+            && (o.order.price - order.price).abs() < 1e-9)
+}
 
 pub struct TraderConf {
     pub rest_uri: String,
     pub streaming_uri: String,
     pub token: String,
+    /// Брокерский счет, `None` - счет по умолчанию
+    pub account: Option<String>,
+    /// Возраст данных, после которого стратегии их не используют
+    pub max_data_age: Duration,
+    /// Через хаб данные стриминга трейдера получают и другие компоненты
+    pub hub: StreamingHub,
 }
 
+/// Глубина стакана при подписке
+const ORDERBOOK_DEPTH: u32 = 10;
+/// Если стриминг так долго недоступен, стаканы опрашиваются через REST
+const STREAMING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Валюты, в которых показывается итоговая стоимость портфеля
+const BASE_CURRENCIES: &[Currency] = &[Currency::RUB, Currency::USD];
+
 pub struct Trader<S> {
+    token: String,
+    rest_uri: String,
     sender: Sender<Response<S>>,
     receiver: Receiver<Request<S>>,
-    streaming: ServiceHandle<StreamingRequest, StreamingResponse>,
-    rest: ServiceHandle<RestRequest, RestResponse>,
+    streaming: StreamingHandle,
+    rest: ServiceHandle<(RequestId, RestRequest), (RequestId, RestResponse)>,
     market: Market,
     strategies: HashMap<Key, S>,
+    origins: HashMap<RequestId, Origin>,
+    owners: HashMap<String, Owner>,
+    /// Подписки стриминга и кому они нужны
+    demand: Demand,
+    /// С какого момента нет соединения со стримингом
+    streaming_down: Option<Instant>,
+    /// Номера соединений стриминга, которые сейчас отключены
+    shards_down: HashSet<usize>,
+    /// Пользователю сообщили о потере соединения
+    streaming_outage: bool,
+    /// Стаканы, запрошенные через REST и еще не полученные
+    orderbook_requests: HashSet<String>,
+    /// Заявки без ответа брокера: до сверки с портфелем отслеживаются под номером запроса
+    unconfirmed: HashSet<String>,
 }
 
 impl<S: Strategy + Send + Clone + 'static> Trader<S> {
     pub fn start(conf: TraderConf) -> ServiceHandle<Request<S>, Response<S>> {
         let (sender, r) = async_channel::bounded(1000);
         let (s, receiver) = async_channel::bounded(1000);
-        let TraderConf{rest_uri, streaming_uri, token} = conf;
+        let TraderConf{rest_uri, streaming_uri, token, account, max_data_age, hub} = conf;
+        let mut market = Market::default();
+        market.set_max_age(max_data_age);
         let trader = Self {
             sender, 
             receiver, 
-            streaming: Streaming::start(token.clone(), streaming_uri), 
-            rest: Rest::start(token, rest_uri), 
-            market: Default::default(),
+            streaming: Streaming::start(token.clone(), streaming_uri, hub), 
+            rest: Rest::start(token.clone(), rest_uri.clone(), account), 
+            token,
+            rest_uri,
+            market,
             strategies: Default::default(),
+            origins: Default::default(),
+            owners: Default::default(),
+            demand: Default::default(),
+            streaming_down: Some(Instant::now()),
+            shards_down: Default::default(),
+            streaming_outage: false,
+            orderbook_requests: Default::default(),
+            unconfirmed: Default::default(),
         };
         tokio::spawn(async move {
             match trader.run().await {
@@ -48,29 +208,49 @@ impl<S: Strategy + Send + Clone + 'static> Trader<S> {
 
     async fn run(mut self) -> Result<(), ChannelStopped> {
         log::info!("Trader started");
-        let mut timer = tokio::time::interval(std::time::Duration::from_secs(7));
-        self.rest.send(RestRequest::Instruments).await?;
+        let mut timer = tokio::time::interval(Duration::from_secs(7));
+        let mut refresh_timer = tokio::time::interval(Duration::from_secs(3600));
+        match InstrumentIndex::load(instruments::CACHE_PATH).await {
+            Some(index) if !index.is_stale() => {
+                log::info!("instruments loaded from cache: {}", index.stocks().len());
+                self.update_instruments(index).await?;
+            }
+            _ => { self.send_rest(RestRequest::Instruments, Origin::Trader).await?; }
+        }
+        refresh_timer.tick().await;
         loop {
             tokio::select! {
-                msg = self.streaming.recv() => {
-                    self.update_market_from_streaming(msg?);
+                // стратегии пересчитываются один раз на пачку событий стриминга
+                events = self.streaming.recv() => {
+                    for event in events? {
+                        self.on_streaming(event).await?;
+                    }
                 }
                 msg = self.rest.recv() => {
-                    self.update_market_from_rest(msg?).await?;
+                    let (id, msg) = msg?;
+                    self.update_market_from_rest(id, msg).await?;
                 }
                 msg = self.receiver.recv() => {
                     let msg = msg.map_err(|_|ChannelStopped)?;
                     self.process_request(msg).await?;
                 }
                 _ = timer.tick() => {
-                    use crate::rest::entities::Request;
-                    self.rest.send(Request::Portfolio).await?;
+                    self.send_rest(RestRequest::Portfolio, Origin::Trader).await?;
+                    self.poll_orderbooks().await?;
+                }
+                _ = refresh_timer.tick() => {
+                    if self.market.instruments().is_stale() {
+                        self.send_rest(RestRequest::Instruments, Origin::Trader).await?;
+                    }
                 }
             }
             let market = &self.market;
-            let decisions: Vec<_> = self.strategies.values_mut().map(|s|s.make_decision(market).into_iter()).flatten().collect();
-            for decision in decisions {
-                self.process_decision(decision).await?;
+            let decisions: Vec<_> = self.strategies.iter_mut()
+                .filter(|(_, s)| is_trading(market, &**s) && has_instruments(market, &**s) && has_fresh_data(market, &**s))
+                .flat_map(|(key, s)| s.make_decision(market).into_iter().map(move |d|(key.clone(), d)))
+                .collect();
+            for (key, decision) in decisions {
+                self.process_decision(key, decision).await?;
             }
         }
     }
@@ -78,73 +258,594 @@ impl<S: Strategy + Send + Clone + 'static> Trader<S> {
     async fn process_request(&mut self, request: entities::Request<S>) -> Result<(), ChannelStopped> {
         use entities::*;
         match request {
-            Request::Portfolio => self.sender.send(Response::Portfolio(self.market.portfolio())).await?,
-            Request::AddStrategy(k, s) => { 
-                self.strategies.insert(k, s); 
-                let strategies = self.strategies.clone();
-                self.sender.send(Response::Strategies(strategies)).await?;
-            }
-            Request::RemoveStrategy(k) => { self.strategies.remove(&k); }
-            Request::Strategies => unimplemented!()
+            Request::Portfolio => self.sender.send(Response::Portfolio(self.market.portfolio(BASE_CURRENCIES))).await?,
+            Request::AddStrategy(k, s) => {
+                let conflicts = self.conflicts(&k, &s);
+                if conflicts.is_empty() {
+                    self.add_strategy(k, s).await?;
+                } else {
+                    log::warn!("strategy {} conflicts with {:?}", k, conflicts);
+                    self.sender.send(Response::Conflict(k, s, conflicts)).await?;
+                }
+            }
+            Request::ForceAddStrategy(k, s) => self.add_strategy(k, s).await?,
+            Request::RemoveStrategy(k) => {
+                self.strategies.remove(&k);
+                self.update_demand(Consumer::Strategy(k), Vec::new()).await?;
+            }
+            Request::ConfigureStrategy(k, param, value) => self.configure_strategy(k, param, value).await?,
+            Request::Strategies => unimplemented!(),
+            Request::Operations { from, to, figi } => {
+                self.send_rest(RestRequest::Operations { from, to, figi }, Origin::Trader).await?;
+            }
+            Request::Accounts => {
+                self.send_rest(RestRequest::Accounts, Origin::Trader).await?;
+            }
+            Request::SelectAccount(account) => self.select_account(account).await?,
+            Request::Sandbox(request) => {
+                self.send_rest(RestRequest::Sandbox(request), Origin::Trader).await?;
+            }
         };
         Ok(())
     }
 
 
-    async fn process_decision(&mut self, decision: Decision) -> Result<(), ChannelStopped> {
+    /// Стратегии и подписки остаются, заявки и позиции прежнего счета забываются
+    async fn select_account(&mut self, account: Option<String>) -> Result<(), ChannelStopped> {
+        log::info!("switching to account {:?}", account);
+        self.rest = Rest::start(self.token.clone(), self.rest_uri.clone(), account);
+        self.origins.clear();
+        self.owners.clear();
+        self.unconfirmed.clear();
+        self.orderbook_requests.clear();
+        self.market.reset_portfolio();
+        if self.market.instruments().stocks().is_empty() {
+            self.send_rest(RestRequest::Instruments, Origin::Trader).await?;
+        }
+        self.send_rest(RestRequest::Portfolio, Origin::Trader).await?;
+        Ok(())
+    }
+
+    /// Другие стратегии, торгующие теми же инструментами
+    fn conflicts(&self, key: &Key, strategy: &S) -> Vec<Key> {
+        let figis = strategy.figis();
+        self.strategies.iter()
+            .filter(|(k, s)| *k != key && s.figis().iter().any(|figi|figis.contains(figi)))
+            .map(|(k, _)|k.clone())
+            .collect()
+    }
+
+    /// Заменяет подписки потребителя. Стакан сразу запрашивается через REST
+    async fn update_demand(&mut self, consumer: Consumer, requests: Vec<StreamingRequest>) -> Result<(), ChannelStopped> {
+        let changes = self.demand.set(consumer, requests);
+        for request in changes.subscribe {
+            if let StreamingRequest::OrderbookSubscribe { figi, depth } = &request {
+                self.request_orderbook(figi.clone(), *depth).await?;
+            }
+            self.send_streaming(request).await?;
+        }
+        for request in changes.unsubscribe {
+            self.send_streaming(request).await?;
+        }
+        Ok(())
+    }
+
+    /// Стратегии нужны стакан и статус торгов по каждому ее инструменту
+    async fn update_strategy_demand(&mut self, key: &Key) -> Result<(), ChannelStopped> {
+        let figis = self.strategies.get(key).map(|s|s.figis()).unwrap_or_default();
+        let requests = figis.into_iter()
+            .flat_map(|figi|vec![
+                StreamingRequest::InfoSubscribe { figi: figi.clone() },
+                StreamingRequest::OrderbookSubscribe { figi, depth: ORDERBOOK_DEPTH },
+            ])
+            .collect();
+        self.update_demand(Consumer::Strategy(key.clone()), requests).await
+    }
+
+    /// Стриминг недоступен дольше допустимого: стаканы берем из REST
+    fn streaming_polling(&self) -> bool {
+        matches!(self.streaming_down, Some(since) if since.elapsed() > STREAMING_TIMEOUT)
+    }
+
+    async fn on_streaming(&mut self, event: StreamingEvent) -> Result<(), ChannelStopped> {
+        match event {
+            StreamingEvent::Data(msg) => self.update_market_from_streaming(msg),
+            StreamingEvent::Error { request, error } => self.on_streaming_error(request, error).await?,
+            StreamingEvent::Connected(shard) => {
+                log::info!("streaming connection {} connected", shard);
+                self.on_shard_up(shard).await?;
+            }
+            StreamingEvent::Closed(shard) => {
+                log::info!("streaming connection {} closed", shard);
+                self.on_shard_up(shard).await?;
+            }
+            StreamingEvent::Disconnected(shard) => {
+                log::warn!("streaming connection {} disconnected", shard);
+                self.shards_down.insert(shard);
+                self.market.set_feed(None);
+                if self.streaming_down.is_none() {
+                    self.streaming_down = Some(Instant::now());
+                }
+                if !self.streaming_outage {
+                    self.streaming_outage = true;
+                    self.sender.send(Response::StreamingLost).await?;
+                }
+            }
+            StreamingEvent::Resubscribed(shard, count) => log::info!("streaming connection {} resubscribed to {} streams", shard, count),
+        }
+        Ok(())
+    }
+
+    /// Закрытое соединение тоже не мешает считать стриминг доступным
+    async fn on_shard_up(&mut self, shard: usize) -> Result<(), ChannelStopped> {
+        self.shards_down.remove(&shard);
+        if !self.shards_down.is_empty() {
+            return Ok(());
+        }
+        if self.streaming_down.take().is_some() {
+            self.market.set_feed(Some(SystemTime::now()));
+        }
+        if self.streaming_outage {
+            self.streaming_outage = false;
+            self.sender.send(Response::StreamingRestored).await?;
+        }
+        Ok(())
+    }
+
+    /// Пока стриминг недоступен, стаканы опрашиваются через REST
+    async fn poll_orderbooks(&mut self) -> Result<(), ChannelStopped> {
+        if !self.streaming_polling() {
+            return Ok(());
+        }
+        let figis: Vec<_> = self.demand.orderbooks().map(str::to_owned).collect();
+        log::warn!("no streaming connection, polling {} orderbooks", figis.len());
+        for figi in figis {
+            self.request_orderbook(figi, ORDERBOOK_DEPTH).await?;
+        }
+        Ok(())
+    }
+
+    /// Запрашивает стакан через REST, если он уже не запрошен: иначе опрос при медленном ответе копит очередь
+    async fn request_orderbook(&mut self, figi: String, depth: u32) -> Result<(), ChannelStopped> {
+        if self.orderbook_requests.insert(figi.clone()) {
+            self.send_rest(RestRequest::Orderbook { figi, depth }, Origin::Trader).await?;
+        }
+        Ok(())
+    }
+
+    async fn add_strategy(&mut self, key: Key, strategy: S) -> Result<(), ChannelStopped> {
+        self.strategies.insert(key.clone(), strategy);
+        self.update_strategy_demand(&key).await?;
+        let strategies = self.strategies.clone();
+        self.sender.send(Response::Strategies(strategies)).await?;
+        Ok(())
+    }
+
+    /// Меняет параметр работающей стратегии, не сбрасывая ее состояние
+    async fn configure_strategy(&mut self, key: Key, param: String, value: String) -> Result<(), ChannelStopped> {
+        let mut strategy = match self.strategies.get(&key) {
+            Some(s) => s.clone(),
+            None => {
+                let msg = crate::strategy::ConfigError::STRATEGY_NOT_FOUND.to_string();
+                self.sender.send(Response::StrategyError(key, msg)).await?;
+                return Ok(())
+            }
+        };
+        if let Err(e) = strategy.configure(&param, value) {
+            self.sender.send(Response::StrategyError(key, e.to_string())).await?;
+            return Ok(());
+        }
+        // смена инструмента не должна обходить проверку при добавлении
+        let conflicts = self.conflicts(&key, &strategy);
+        if !conflicts.is_empty() {
+            log::warn!("strategy {} reconfigured to conflict with {:?}", key, conflicts);
+            let msg = format!("этими инструментами уже торгуют {}", conflicts.join(", "));
+            self.sender.send(Response::StrategyError(key, msg)).await?;
+            // чат уже показал новое значение: возвращаем ему действующие настройки
+            self.sender.send(Response::Strategies(self.strategies.clone())).await?;
+            return Ok(());
+        }
+        self.strategies.insert(key.clone(), strategy);
+        self.update_strategy_demand(&key).await?;
+        self.sender.send(Response::Strategies(self.strategies.clone())).await?;
+        Ok(())
+    }
+
+    async fn process_decision(&mut self, strategy: Key, decision: Decision) -> Result<(), ChannelStopped> {
         match decision {
             Decision::Order(order) => {
-                let stock = self.market.state_mut(&order.figi);
-                let key = SystemTime::now();
-                stock.new_orders.insert(key, order.clone());
-                self.rest.send(crate::rest::entities::Request::LimitOrder(key, order)).await?;
+                // цену приводим к шагу цены биржи; стратегии без справочника до сюда не доходят,
+                // кроме заявок на инструменты не из `figis()`
+                let order = match self.market.stock(&order.figi) {
+                    Some(stock) => Order { price: stock.round_price(order.price), ..order },
+                    None => {
+                        let reason = "нет данных об инструменте".to_owned();
+                        log::warn!("strategy {} order {:?} vetoed: {}", strategy, order, reason);
+                        self.notify(&strategy, OrderEvent::Rejected(order, reason));
+                        return Ok(());
+                    }
+                };
+                let figi = order.figi.clone();
+                let request = RestRequest::LimitOrder(order.clone());
+                let id = self.send_rest(request, Origin::Strategy(strategy, Decision::Order(order.clone()))).await?;
+                self.market.state_mut(&figi).new_orders.insert(id, order);
             }
         }
         Ok(())
     }
-    
+
+    async fn update_instruments(&mut self, index: InstrumentIndex) -> Result<(), ChannelStopped> {
+        self.market.update_stocks(index);
+        self.sender.send(Response::Stocks(self.market.instruments().stocks().to_vec())).await?;
+        Ok(())
+    }
+
+    async fn send_rest(&mut self, request: RestRequest, origin: Origin) -> Result<RequestId, ChannelStopped> {
+        let id = RequestId::new();
+        match origin {
+            Origin::Trader => log::debug!("{} {:?} from {}", id, request, origin),
+            Origin::Strategy(..) => {
+                log::info!("{} {:?} from {}", id, request, origin);
+                self.origins.insert(id, origin);
+            }
+        }
+        self.rest.send((id, request)).await?;
+        Ok(id)
+    }
+
+    async fn send_streaming(&mut self, request: StreamingRequest) -> Result<RequestId, ChannelStopped> {
+        let id = RequestId::new();
+        self.streaming.send((id, request)).await?;
+        Ok(id)
+    }
+
+    /// Стратегия, по решению которой отправлен запрос
+    fn take_owner(&mut self, id: RequestId) -> Option<Key> {
+        match self.origins.remove(&id)? {
+            Origin::Strategy(key, _) => Some(key),
+            Origin::Trader => None,
+        }
+    }
+
+    fn notify(&mut self, strategy: &Key, event: OrderEvent) {
+        let strategy = match self.strategies.get_mut(strategy) {
+            Some(s) => s,
+            None => return,
+        };
+        match event {
+            OrderEvent::Accepted(state) => strategy.on_accepted(&state),
+            OrderEvent::PartiallyFilled(state, fill) => strategy.on_partially_filled(&state, fill),
+            OrderEvent::Filled(state, fill) => strategy.on_filled(&state, fill),
+            OrderEvent::Cancelled(state) => strategy.on_cancelled(&state),
+            OrderEvent::Rejected(order, reason) => strategy.on_rejected(&order, &reason),
+        }
+    }
+
+    fn on_order_placed(&mut self, id: RequestId, state: OrderState) {
+        log::info!("{} order {} accepted", id, state.order_id);
+        let stock = self.market.state_mut(&state.order.figi);
+        stock.new_orders.remove(&id);
+        stock.inwork_orders.insert(state.order_id.clone(), state.clone());
+        let owner = match self.take_owner(id) {
+            Some(owner) => owner,
+            None => return,
+        };
+        self.notify(&owner, OrderEvent::Accepted(state.clone()));
+        let fill = Fill { limit_price: state.order.price, quantity: state.executed };
+        if state.executed >= state.order.quantity {
+            self.notify(&owner, OrderEvent::Filled(state, fill));
+        } else {
+            if state.executed > 0 {
+                self.notify(&owner, OrderEvent::PartiallyFilled(state.clone(), fill));
+            }
+            self.owners.insert(state.order_id, Owner { strategy: owner, accepted: SystemTime::now() });
+        }
+    }
+
+    /// Об отклонении узнают стратегия и ее владелец в чате
+    async fn on_order_rejected(&mut self, id: RequestId, order: Order, reason: String) -> Result<(), ChannelStopped> {
+        log::warn!("{} order rejected: {:?}, reason: {}", id, order, reason);
+        self.market.state_mut(&order.figi).new_orders.remove(&id);
+        if let Some(owner) = self.take_owner(id) {
+            self.sender.send(Response::StrategyError(owner.clone(), format!("Заявка отклонена: {}", reason))).await?;
+            self.notify(&owner, OrderEvent::Rejected(order, reason));
+        }
+        Ok(())
+    }
+
+    async fn on_order_unconfirmed(&mut self, id: RequestId, order: Order, e: RestError) -> Result<(), ChannelStopped> {
+        log::warn!("{} order {:?} may be placed: {}", id, order, e);
+        self.market.state_mut(&order.figi).new_orders.remove(&id);
+        let owner = match self.take_owner(id) {
+            Some(owner) => owner,
+            None => return Ok(()),
+        };
+        let state = OrderState { order_id: id.to_string(), order, status: OrderStatus::PendingNew, executed: 0 };
+        self.market.state_mut(&state.order.figi).inwork_orders.insert(state.order_id.clone(), state);
+        self.sender.send(Response::StrategyError(owner.clone(), format!("Неизвестно, принята ли заявка ({}), проверю по портфелю", e))).await?;
+        self.owners.insert(id.to_string(), Owner { strategy: owner, accepted: SystemTime::now() });
+        self.unconfirmed.insert(id.to_string());
+        Ok(())
+    }
+
+    /// Ищет заявки без ответа среди активных заявок снимка
+    fn confirm_orders(&mut self, time: SystemTime, orders: &[OrderState]) {
+        let ids: Vec<String> = self.unconfirmed.iter()
+            .filter(|id|matches!(self.owners.get(*id), Some(owner) if owner.accepted < time))
+            .cloned()
+            .collect();
+        for id in ids {
+            self.unconfirmed.remove(&id);
+            let state = match self.market.inwork_orders().find(|o|o.order_id == id) {
+                Some(state) => state.clone(),
+                None => continue,
+            };
+            let placed = match find_placed(&self.owners, orders, &state.order) {
+                Some(placed) => placed.clone(),
+                None => continue,
+            };
+            log::info!("{} order {} found in portfolio", id, placed.order_id);
+            let owner = self.owners.remove(&id).unwrap();
+            let stock = self.market.state_mut(&state.order.figi);
+            stock.inwork_orders.remove(&id);
+            // исполненную часть сообщит сверка: считаем от нуля
+            let known = OrderState { order_id: placed.order_id.clone(), executed: 0, ..state };
+            stock.inwork_orders.insert(known.order_id.clone(), known);
+            self.notify(&owner.strategy, OrderEvent::Accepted(placed.clone()));
+            self.owners.insert(placed.order_id, owner);
+        }
+    }
+
+    /// Сообщает стратегиям об исполнении и снятии заявок по снимку портфеля на момент `time`
+    fn reconcile(&mut self, time: SystemTime, positions: &[(String, Position)], orders: &[OrderState]) {
+        let events = reconcile_orders(&self.market, &self.owners, time, positions, orders);
+        for (finished, event) in events {
+            let order_id = match &event {
+                OrderEvent::PartiallyFilled(state, _) | OrderEvent::Filled(state, _) | OrderEvent::Cancelled(state) => state.order_id.clone(),
+                _ => continue,
+            };
+            let owner = if finished {
+                self.owners.remove(&order_id).map(|o|o.strategy)
+            } else {
+                self.owners.get(&order_id).map(|o|o.strategy.clone())
+            };
+            if let Some(owner) = owner {
+                self.notify(&owner, event);
+            }
+        }
+    }
+
     fn update_market_from_streaming(&mut self, msg: StreamingResponse) {
         let StreamingResponse { time, kind } = msg;
         use crate::streaming::entities::ResponseType;
         match kind {
-            ResponseType::Candle(_) => {}
+            ResponseType::Candle(candle) => {
+                let figi = candle.figi.clone();
+                if self.market.candles_stale(&figi) {
+                    if let Some(age) = self.market.candles_age(&figi) {
+                        log::info!("{}: candles resumed after {:?}", figi, age);
+                    }
+                }
+                self.market.state_mut(&figi).update_candle(candle.into());
+            }
             ResponseType::Orderbook {figi, depth: _, bids, asks,} => {
-                self.market.state_mut(&figi).orderbook = Orderbook { time, bids, asks };
+                self.market.state_mut(&figi).orderbook = Orderbook { time, received: SystemTime::now(), streamed: true, bids, asks };
+            }
+            ResponseType::Info {figi, trade_status, min_price_increment, lot} => {
+                self.market.update_instrument(&figi, trade_status, min_price_increment, lot);
             }
-            ResponseType::Info {..} => {}
             ResponseType::Error { .. } => {}
         }
     }
 
-    async fn update_market_from_rest(&mut self, msg: RestResponse) -> Result<(), ChannelStopped> {
+    /// Ошибку подписки получают стратегии, которым нужен этот инструмент, а если таких нет - чат
+    async fn on_streaming_error(&mut self, request: Option<(RequestId, StreamingRequest)>, error: String) -> Result<(), ChannelStopped> {
+        use StreamingRequest::*;
+        let figi = match &request {
+            Some((_, CandleSubscribe { figi, .. })) | Some((_, OrderbookSubscribe { figi, .. })) | Some((_, InfoSubscribe { figi })) => Some(figi.clone()),
+            _ => None,
+        };
+        let owners: Vec<_> = match &request {
+            Some((_, request)) => self.demand.consumers(request).filter_map(|c|match c {
+                Consumer::Strategy(key) => Some(key.clone()),
+                _ => None,
+            }).collect(),
+            None => Vec::new(),
+        };
+        let text = match &figi {
+            Some(figi) => format!("Не удалось подписаться на {}: {}", self.market.stock(figi).map_or(figi.as_str(), |s|s.ticker.as_str()), error),
+            None => format!("Ошибка стриминга: {}", error),
+        };
+        if owners.is_empty() {
+            self.sender.send(Response::StreamingError(text)).await?;
+        } else {
+            for key in owners {
+                self.sender.send(Response::StrategyError(key, text.clone())).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_market_from_rest(&mut self, id: RequestId, msg: RestResponse) -> Result<(), ChannelStopped> {
         match msg {
             RestResponse::Err(request, e) => {
-                if let crate::rest::entities::Request::LimitOrder(key, order, ..) = request {
-                    self.market.state_mut(&order.figi).new_orders.remove(&key);
+                log::error!("{} ERR from rest on {:?}: {:?}", id, request, e);
+                if let RestRequest::Orderbook { figi, .. } = &request {
+                    self.orderbook_requests.remove(figi);
+                }
+                match request {
+                    // владельцу заявки об ошибке сообщит стратегия
+                    RestRequest::LimitOrder(order) if e.is_rejection() => self.on_order_rejected(id, order, e.to_string()).await?,
+                    // брокер мог принять заявку: это покажет следующий портфель
+                    RestRequest::LimitOrder(order) => self.on_order_unconfirmed(id, order, e).await?,
+                    _ if !e.is_retryable() => self.sender.send(Response::RestError(e)).await?,
+                    _ => {}
                 }
-                log::error!("ERR from rest!!! {:?}", e);
             }
+            RestResponse::OrderRejected(order, reason) => self.on_order_rejected(id, order, reason).await?,
             RestResponse::Stocks(stocks) => {
-                self.sender.send(Response::Stocks(stocks.clone())).await?;
-                self.market.update_stocks(stocks);
+                self.update_instruments(InstrumentIndex::new(stocks)).await?;
+                if let Err(e) = self.market.instruments().save(instruments::CACHE_PATH).await {
+                    log::error!("cannot save instruments cache: {}", e);
+                }
             },
+            RestResponse::Orderbook { figi, orderbook, trade_status } => {
+                self.orderbook_requests.remove(&figi);
+                // снимок из REST мог устареть, пока шел запрос: время биржи в нем неизвестно,
+                // поэтому сравниваем, когда запрос ушел, с получением стакана из стриминга
+                let polling = self.streaming_polling();
+                let state = self.market.state_mut(&figi);
+                if state.orderbook.received <= orderbook.received {
+                    state.orderbook = orderbook;
+                } else {
+                    log::debug!("{}: orderbook requested at {} is older than streamed at {}", figi, orderbook.time, state.orderbook.time);
+                }
+                // статус из стриминга точнее: различает аукционы и перерывы
+                if state.trade_status.is_none() || polling {
+                    state.trade_status = Some(trade_status);
+                }
+            }
             RestResponse::Candles { figi, candles } => {
-                self.market.state_mut(&figi).candles.extend(candles.into_iter());
-            }
-            RestResponse::Order(key, state) => {
-                let stock = self.market.state_mut(&state.order.figi);
-                stock.new_orders.remove(&key);
-                stock.inwork_orders.insert(state.order_id.clone(),state);
-            }
-            RestResponse::Portfolio{positions, orders} => {
-                for (figi, _) in &positions {
-                    let figi = figi.clone();
-                    let depth = 10; //TODO: надо бы параметризировать
-                    self.streaming.send(StreamingRequest::OrderbookSubscribe {figi, depth}).await?;
+                let state = self.market.state_mut(&figi);
+                state.candles.extend(candles.into_iter());
+                state.candles_received = Some(SystemTime::now());
+            }
+            RestResponse::Order(state) => self.on_order_placed(id, state),
+            RestResponse::Operations(operations) => self.sender.send(Response::Operations(operations)).await?,
+            RestResponse::Accounts(accounts) => self.sender.send(Response::Accounts(accounts)).await?,
+            RestResponse::SandboxRegistered(account) => self.sender.send(Response::SandboxRegistered(account)).await?,
+            RestResponse::SandboxDone(request) => {
+                // позиции в песочнице поменялись: сразу обновляем портфель
+                self.send_rest(RestRequest::Portfolio, Origin::Trader).await?;
+                self.sender.send(Response::SandboxDone(request)).await?;
+            }
+            RestResponse::Portfolio{time, positions, currencies, orders} => {
+                let books = |figis: Vec<String>| -> Vec<_> {figis.into_iter()
+                    .map(|figi|StreamingRequest::OrderbookSubscribe { figi, depth: ORDERBOOK_DEPTH })
+                    .collect()};
+                let held = books(positions.iter().map(|(figi, _)|figi.clone()).collect());
+                let fresh: Vec<_> = self.market.inwork_orders()
+                    .filter(|o|matches!(self.owners.get(&o.order_id), Some(owner) if owner.accepted >= time))
+                    .cloned()
+                    .collect();
+                self.confirm_orders(time, &orders);
+                self.reconcile(time, &positions, &orders);
+                self.market.update_portfolio(positions, currencies, orders);
+                for state in fresh {
+                    self.market.state_mut(&state.order.figi).inwork_orders.entry(state.order_id.clone()).or_insert(state);
                 }
-                self.market.update_portfolio(positions, orders)
+                self.update_demand(Consumer::Positions, held).await?;
+                self.update_demand(Consumer::Valuation, books(self.market.fx_figis())).await?;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Case {
+        market: Market,
+        owners: HashMap<String, Owner>,
+        snapshot: SystemTime,
+    }
+
+    impl Case {
+        fn new() -> Self {
+            Self { market: Market::default(), owners: HashMap::new(), snapshot: SystemTime::now() }
+        }
+
+        fn order(&mut self, id: &str, figi: &str, kind: OrderKind, quantity: u32, accepted: SystemTime) -> OrderState {
+            let order = Order { figi: figi.to_owned(), kind, price: 10.0, quantity };
+            let state = OrderState { order_id: id.to_owned(), order, status: OrderStatus::New, executed: 0 };
+            self.market.state_mut(figi).inwork_orders.insert(id.to_owned(), state.clone());
+            self.owners.insert(id.to_owned(), Owner { strategy: "s".to_owned(), accepted });
+            state
+        }
+
+        fn run(&self, lots: &[(&str, i32)], active: &[OrderState]) -> Vec<(bool, String, &'static str, u32)> {
+            let positions: Vec<_> = lots.iter().map(|(figi, lots)|(figi.to_string(), Position { lots: *lots, ..Default::default() })).collect();
+            let mut events: Vec<_> = reconcile_orders(&self.market, &self.owners, self.snapshot, &positions, active).into_iter()
+                .map(|(finished, event)|match event {
+                    OrderEvent::PartiallyFilled(s, fill) => (finished, s.order_id, "partial", fill.quantity),
+                    OrderEvent::Filled(s, fill) => (finished, s.order_id, "filled", fill.quantity),
+                    OrderEvent::Cancelled(s) => (finished, s.order_id, "cancelled", s.executed),
+                    _ => unreachable!(),
+                })
+                .collect();
+            events.sort();
+            events
+        }
+    }
+
+    fn before(case: &Case) -> SystemTime {
+        case.snapshot - Duration::from_secs(1)
+    }
+
+    #[test]
+    fn test_reconcile_single() {
+        let mut case = Case::new();
+        let accepted = before(&case);
+        let buy = case.order("1", "A", OrderKind::Buy, 5, accepted);
+        case.order("2", "B", OrderKind::Sell, 3, accepted);
+        case.market.state_mut("B").position.lots = 10;
+        case.order("3", "C", OrderKind::Buy, 4, accepted);
+        // принятая после снимка заявка в нем отсутствует, но не исполнена
+        let late = case.snapshot + Duration::from_secs(1);
+        case.order("4", "D", OrderKind::Buy, 1, late);
+
+        let partial = OrderState { executed: 2, ..buy };
+        let events = case.run(&[("A", 2), ("B", 8)], &[partial]);
+        assert_eq!(events, vec![
+            (false, "1".to_owned(), "partial", 2),
+            (false, "2".to_owned(), "partial", 2),
+            (true, "2".to_owned(), "cancelled", 2),
+            (true, "3".to_owned(), "cancelled", 0),
+        ]);
+    }
+
+    #[test]
+    fn test_find_placed() {
+        let mut case = Case::new();
+        let accepted = before(&case);
+        let known = case.order("1", "A", OrderKind::Buy, 5, accepted);
+        let order = known.order.clone();
+        assert!(find_placed(&case.owners, std::slice::from_ref(&known), &order).is_none());
+
+        let other = |id: &str, order: Order| OrderState { order_id: id.to_owned(), order, ..known.clone() };
+        let orders = vec![
+            other("2", Order { quantity: 4, ..order.clone() }),
+            other("3", Order { kind: OrderKind::Sell, ..order.clone() }),
+            other("4", order.clone()),
+        ];
+        assert_eq!(find_placed(&case.owners, &orders, &order).map(|o|o.order_id.as_str()), Some("4"));
+    }
+
+    #[test]
+    fn test_reconcile_same_figi() {
+        let mut case = Case::new();
+        let accepted = before(&case);
+        case.order("1", "A", OrderKind::Buy, 5, accepted);
+        case.order("2", "A", OrderKind::Buy, 5, accepted);
+        // изменение позиции делится между заявками: какая исполнена целиком, заранее не известно
+        let events = case.run(&[("A", 7)], &[]);
+        let filled: u32 = events.iter().filter(|e|e.2 != "cancelled").map(|e|e.3).sum();
+        assert_eq!(filled, 7);
+        assert_eq!(events.iter().filter(|e|e.2 == "filled").count(), 1);
+        assert_eq!(events.iter().filter(|e|e.2 == "cancelled").map(|e|e.3).collect::<Vec<_>>(), vec![2]);
+
+        // встречные заявки по одной бумаге: продажа исполнена, покупка снята
+        let mut case = Case::new();
+        let accepted = before(&case);
+        case.market.state_mut("A").position.lots = 10;
+        case.order("1", "A", OrderKind::Buy, 5, accepted);
+        case.order("2", "A", OrderKind::Sell, 4, accepted);
+        let events = case.run(&[("A", 6)], &[]);
+        assert_eq!(events, vec![
+            (true, "1".to_owned(), "cancelled", 0),
+            (true, "2".to_owned(), "filled", 4),
+        ]);
+    }
+}